@@ -0,0 +1,61 @@
+use std::fmt;
+use crate::mergebams::HeaderIssue;
+
+/// Everything that can stop a merge, subset or peek. The extendr helpers hand
+/// these to R as conditions of class `class()`, `mergebamsR_error` and `error`.
+#[derive(Debug)]
+pub enum Error {
+    /// An argument passed from R is invalid.
+    Argument(String),
+    /// An input cannot be found, opened or read.
+    Input { path: String, message: String },
+    /// An output cannot be created or written.
+    Output { path: String, message: String },
+    /// The sequence dictionaries of the inputs cannot be merged.
+    Header(Vec<HeaderIssue>),
+    /// No read was written to any output.
+    NoReads(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn input<P: AsRef<str>, E: fmt::Display>(path: P, err: E) -> Error {
+        Error::Input { path: path.as_ref().to_string(), message: err.to_string() }
+    }
+
+    pub fn output<P: AsRef<str>, E: fmt::Display>(path: P, err: E) -> Error {
+        Error::Output { path: path.as_ref().to_string(), message: err.to_string() }
+    }
+
+    /// The most specific R class of the condition.
+    pub fn class(&self) -> &'static str {
+        match self {
+            Error::Argument(_) => "mergebamsR_argument_error",
+            Error::Input { .. } => "mergebamsR_input_error",
+            Error::Output { .. } => "mergebamsR_output_error",
+            Error::Header(_) => "mergebamsR_header_error",
+            Error::NoReads(_) => "mergebamsR_empty_error",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Argument(message) => write!(f, "{}", message),
+            Error::Input { path, message } => write!(f, "unable to read {}: {}", path, message),
+            Error::Output { path, message } => write!(f, "unable to write {}: {}", path, message),
+            Error::Header(issues) => {
+                write!(f, "BAM header sequences do not match - you will need to fix this before merging bams:")?;
+                for issue in issues.iter() {
+                    write!(f, "\n{}: {} {} (expected '{}', found '{}')", issue.bam, issue.kind.as_str(), issue.reference, issue.expected, issue.found)?;
+                }
+                Ok(())
+            },
+            Error::NoReads(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
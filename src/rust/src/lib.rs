@@ -20,7 +20,9 @@ extern crate human_panic;
 
 // use bam::record::tags;
 use extendr_api::prelude::*;
+use std::collections::HashSet;
 // use rust_htslib::htslib::sam_fields;
+mod errors;
 mod mergebams;
 mod utils;
 mod subsetbam;
@@ -30,90 +32,249 @@ mod subsetbam;
 /// @export
 /// @keywords internal
 #[extendr]
-fn mergebams_rust_helper(bams: Robj, out_path: Robj, names: Robj, prefixes: Robj) {
+#[allow(clippy::too_many_arguments)]
+fn mergebams_rust_helper(bams: Robj, out_path: Robj, names: Robj, tags: Robj, prefixes: Robj, suffixes: Robj, replace_suffix: Robj, sort_order: Robj, reference: Robj, drop_unmatched: Robj, rg_ids: Robj, rg_samples: Robj, rg_libraries: Robj, barcodes: Robj, barcode_tag: Robj, drop_unlisted: Robj, fail_bam: Robj, overwrite: Robj, fasta: Robj, threads: Robj, compression_level: Robj) -> Robj {
     let bam_files: Vec<&str> = match bams.as_str_vector() {
         Some(files) => files,
-        None => {
-                  eprintln!("ERROR: bams is not a string vector");
-                  return
-                },
-    };
-    let out_path: &str = match out_path.as_str_vector() {
-        Some(paths) => paths[0],
-        None => {
-                  eprintln!("ERROR: out_path is not a string");
-                  return
-                },
-    };
-    let prefixes: Vec<&str> = match prefixes.as_str_vector() {
+        None => return argument_error("bams is not a string vector"),
+    };
+    let out_path: &str = match out_path.as_str() {
+        Some(path) => path,
+        None => return argument_error("out_path is not a string"),
+    };
+    let tags: Vec<&str> = match tags.as_str_vector() {
+        Some(tags) => tags,
+        None => return argument_error("tags is not a string vector"),
+    };
+    let replace_suffix: Vec<&str> = match replace_suffix.as_str_vector() {
+        Some(suffixes) => suffixes,
+        None => return argument_error("replace_suffix is not a string vector"),
+    };
+    let prefixes = match string_vectors(&prefixes) {
         Some(prefs) => prefs,
-        None => {
-                  eprintln!("ERROR: prefixes is not a string vector");
-                  return
-                },
-    };
-    let mut read_names: Vec<Option<Vec<String>>> = Vec::new();
-    if let Some(names_list) = names.as_list() {
-        for name in names_list.iter() {
-            if name.1.is_null() {
-                read_names.push(None);
-            } else {
-                let names_vec: Vec<String> = name.1.as_str_vector().unwrap_or_default().into_iter().map(String::from).collect();
-                read_names.push(Some(names_vec));
-            }
+        None => return argument_error("prefixes is not a list of string vectors"),
+    };
+    let suffixes = match string_vectors(&suffixes) {
+        Some(suffs) => suffs,
+        None => return argument_error("suffixes is not a list of string vectors"),
+    };
+    if prefixes.len() != tags.len() || suffixes.len() != tags.len() || replace_suffix.len() != tags.len() {
+        return argument_error("prefixes, suffixes and replace_suffix must have one element per tag");
+    }
+    let mut rewrites: Vec<mergebams::TagRewrite> = Vec::new();
+    for (i, tag) in tags.iter().enumerate() {
+        if tag.len() != 2 {
+            return argument_error(&format!("tag {} is not two characters long", tag))
+        }
+        rewrites.push(mergebams::TagRewrite {
+            tag: [tag.as_bytes()[0], tag.as_bytes()[1]],
+            prefixes: prefixes[i].clone(),
+            suffixes: suffixes[i].clone(),
+            replace_suffix: replace_suffix[i].to_string(),
+        });
+    }
+    let sort_order: &str = match sort_order.as_str() {
+        Some(order) => order,
+        None => return argument_error("sort_order is not a string"),
+    };
+    let reference: &str = match reference.as_str() {
+        Some(reference) => reference,
+        None => return argument_error("reference is not a string"),
+    };
+    let drop_unmatched: bool = match drop_unmatched.as_bool() {
+        Some(drop) => drop,
+        None => return argument_error("drop_unmatched is not a logical"),
+    };
+    let (rg_ids, rg_samples, rg_libraries) = match (rg_ids.as_str_vector(), rg_samples.as_str_vector(), rg_libraries.as_str_vector()) {
+        (Some(ids), Some(samples), Some(libraries)) if samples.len() == ids.len() && libraries.len() == ids.len() => (ids, samples, libraries),
+        _ => return argument_error("read groups, samples and libraries are not string vectors of the same length"),
+    };
+    let assigned: Vec<Option<mergebams::ReadGroup>> = rg_ids
+        .iter()
+        .enumerate()
+        .map(|(i, id)| if id.is_empty() {
+            None
+        } else {
+            Some(mergebams::ReadGroup {
+                id: id.to_string(),
+                sample: rg_samples[i].to_string(),
+                library: rg_libraries[i].to_string(),
+            })
+        })
+        .collect();
+    let barcode_tag: [u8; 2] = match barcode_tag.as_str() {
+        Some(tag) if tag.len() == 2 => [tag.as_bytes()[0], tag.as_bytes()[1]],
+        _ => return argument_error("barcode_tag is not a two character string"),
+    };
+    let drop_unlisted: bool = match drop_unlisted.as_bool() {
+        Some(drop) => drop,
+        None => return argument_error("drop_unlisted is not a logical"),
+    };
+    let fail_bam: Option<String> = match fail_bam.as_str() {
+        Some("") => None,
+        Some(path) => Some(path.to_string()),
+        None => return argument_error("fail_bam is not a string"),
+    };
+    let overwrite: bool = match overwrite.as_bool() {
+        Some(overwrite) => overwrite,
+        None => return argument_error("overwrite is not a logical"),
+    };
+    let fasta: Option<String> = match fasta.as_str() {
+        Some("") => None,
+        Some(path) => Some(path.to_string()),
+        None => return argument_error("fasta is not a string"),
+    };
+    let threads = match threads.as_real() {
+        Some(n) => n as usize,
+        None => return argument_error("threads is not an integer"),
+        };
+    let compression_level = match compression_level.as_real() {
+        Some(n) => n as u8,
+        None => return argument_error("compression_level is not an integer"),
+        };
+    let names = match string_sets(&names) {
+        Some(sets) => sets,
+        None => return argument_error("names is not a list of character vectors or NULLs"),
+    };
+    let barcodes = match string_sets(&barcodes) {
+        Some(sets) => sets,
+        None => return argument_error("barcodes is not a list of character vectors or NULLs"),
+    };
+    let options = mergebams::MergeOptions {
+        threads,
+        compression_level,
+        fail_bam,
+        overwrite,
+        fasta,
+        names,
+        rewrites,
+        sort_order: sort_order.to_string(),
+        reference: reference.to_string(),
+        drop_unmatched,
+        assigned,
+        barcodes,
+        barcode_tag,
+        drop_unlisted,
+    };
+
+    match mergebams::mergebams_rust(bam_files, &out_path, options) {
+        Ok(report) => list!(
+            header_report = header_report(&report.issues),
+            inputs = merge_input_metrics(&report.inputs),
+            outputs = output_metrics(report.outputs.iter().map(|output| (output.path.clone(), output.reads)))
+        ).into(),
+        Err(error) => r_condition(&error),
+    }
+}
+
+/// checkheaders_rust
+/// @export
+/// @keywords internal
+#[extendr]
+fn checkheaders_rust_helper(bams: Robj) -> Robj {
+    let bam_files: Vec<&str> = match bams.as_str_vector() {
+        Some(files) => files,
+        None => return argument_error("bams is not a string vector"),
+    };
+    match mergebams::checkheaders_rust(bam_files) {
+        Ok(issues) => header_report(&issues),
+        Err(error) => r_condition(&error),
+    }
+}
+
+/// Lays out header issues as the columns of a data.frame.
+fn header_report(issues: &[mergebams::HeaderIssue]) -> Robj {
+    list!(
+        bam = issues.iter().map(|issue| issue.bam.clone()).collect::<Vec<String>>(),
+        issue = issues.iter().map(|issue| issue.kind.as_str()).collect::<Vec<&str>>(),
+        reference = issues.iter().map(|issue| issue.reference.clone()).collect::<Vec<String>>(),
+        expected = issues.iter().map(|issue| issue.expected.clone()).collect::<Vec<String>>(),
+        found = issues.iter().map(|issue| issue.found.clone()).collect::<Vec<String>>()
+    ).into()
+}
+
+/// Lays out the read counts of each merged input as the columns of a data.frame.
+fn merge_input_metrics(inputs: &[mergebams::InputMetrics]) -> Robj {
+    let column = |count: fn(&mergebams::InputMetrics) -> u64| inputs.iter().map(|input| count(input) as f64).collect::<Vec<f64>>();
+    list!(
+        input = inputs.iter().map(|input| input.bam.clone()).collect::<Vec<String>>(),
+        reads = column(|input| input.reads),
+        written = column(|input| input.written),
+        missing_tag = column(|input| input.missing_tag),
+        unmatched = column(|input| input.unmatched),
+        unlisted = column(|input| input.unlisted),
+        filtered = column(|input| input.filtered),
+        unreadable = column(|input| input.unreadable),
+        names_not_found = column(|input| input.names_not_found)
+    ).into()
+}
+
+/// Lays out the number of reads written to each output as the columns of a data.frame.
+fn output_metrics<I: Iterator<Item = (String, u64)>>(outputs: I) -> Robj {
+    let (paths, reads): (Vec<String>, Vec<f64>) = outputs.map(|(path, reads)| (path, reads as f64)).unzip();
+    list!(output = paths, reads = reads).into()
+}
+
+/// Converts an R list of character vectors (or `NULL`s) into one set of byte strings per element.
+/// Gives `None` if an element is neither.
+fn string_sets(list: &Robj) -> Option<Vec<Option<HashSet<Vec<u8>>>>> {
+    let mut sets: Vec<Option<HashSet<Vec<u8>>>> = Vec::new();
+    for (_item_str, item_robj) in list.as_list()? {
+        if item_robj.is_null() {
+            sets.push(None);
+        } else {
+            let set: HashSet<Vec<u8>> = item_robj.as_str_vector()?.into_iter().map(|value| value.as_bytes().to_vec()).collect();
+            sets.push(Some(set));
         }
     }
-    // let prefixes: Vec<&str> = prefixes.as_str_vector().unwrap();
+    Some(sets)
+}
 
-    // Assuming mergebamsR::mergebams_rust now accepts Vec<String> instead of Vec<&str>
-    mergebams::mergebams_rust(bam_files, &out_path, read_names, prefixes);
+/// Converts an R list of character vectors into nested `String` vectors.
+fn string_vectors(list: &Robj) -> Option<Vec<Vec<String>>> {
+    let mut out: Vec<Vec<String>> = Vec::new();
+    for (_item_str, item_robj) in list.as_list()? {
+        out.push(item_robj.as_str_vector()?.into_iter().map(String::from).collect());
+    }
+    Some(out)
 }
 
 /// peekbam_rust
 /// @export
 /// @keywords internal
 #[extendr]
-fn peekbam_rust_helper(bam: Robj, n: Robj, field: Robj, tag: Robj) -> Robj{
-    let field: &str = match field.as_str_vector() {
-        Some(fields) => fields[0],
-        None => {
-                  eprintln!("ERROR: field is not a string");
-                  return Robj::from(0)
-                },
-    };
-
-    let bam_file: &str  = match bam.as_str_vector() {
-        Some(files) => files[0],
-        None => {
-                  eprintln!("ERROR: bams is not a string vector");
-                  return Robj::from(0)
-                },
+fn peekbam_rust_helper(bam: Robj, n: Robj, field: Robj, tag: Robj, fasta: Robj) -> Robj{
+    let field: &str = match field.as_str() {
+        Some(field) => field,
+        None => return argument_error("field is not a string"),
+    };
+
+    let bam_file: &str  = match bam.as_str() {
+        Some(file) => file,
+        None => return argument_error("bam is not a string"),
     };
     let n = match n.as_real() {
         Some(n) => n as u64,
-        None => {
-                  eprintln!("ERROR: n is not an integer");
-                  return Robj::from(0)
-                },
+        None => return argument_error("n is not an integer"),
         };
 
 
-    let tag: &str = match tag.as_str_vector() {
-        Some(tags) => tags[0],
-        None => {
-                  eprintln!("ERROR: tag is not a string");
-                  return Robj::from(0)
-                },
+    let tag: &str = match tag.as_str() {
+        Some(tag) => tag,
+        None => return argument_error("tag is not a string"),
+    };
+
+    let fasta: Option<String> = match fasta.as_str() {
+        Some("") => None,
+        Some(path) => Some(path.to_string()),
+        None => return argument_error("fasta is not a string"),
     };
 
     // let mut tags: Result<Vec<&str>> = Ok(Vec::new());
-    let tags = utils::peekbam_rust(bam_file, n, field, tag);
+    let tags = utils::peekbam_rust(bam_file, n, field, tag, fasta.as_deref());
     match tags {
         Ok(tags) => Robj::from(tags),
-        Err(_) => {
-            eprintln!("ERROR: peekbam_rust failed");
-            return Robj::from(0)
-        }
+        Err(error) => r_condition(&error),
     }
     // Robj::from(&tags.unwrap())
 }
@@ -122,38 +283,36 @@ fn peekbam_rust_helper(bam: Robj, n: Robj, field: Robj, tag: Robj) -> Robj{
 /// @export
 /// @keywords internal
 #[extendr]
-fn subsetbam_rust_helper(inputbam: Robj, features: Robj, outputbams: Robj, tag: Robj, cores: Robj, field: Robj, dump_bam: Robj){
+#[allow(clippy::too_many_arguments)]
+fn subsetbam_rust_helper(inputbam: Robj, features: Robj, outputbams: Robj, tag: Robj, cores: Robj, field: Robj, dump_bam: Robj, fasta: Robj, groups: Robj, header: Robj, template: Robj, regions: Robj, bed: Robj, max_open_files: Robj, index: Robj) -> Robj {
     
-    let inputbam: &str  = match inputbam.as_str_vector() {
-        Some(files) => files[0],
-        None => {
-                  eprintln!("ERROR: inputbam is not a string");
-                  return
-                },
+    let inputbam: &str  = match inputbam.as_str() {
+        Some(file) => file,
+        None => return argument_error("inputbam is not a string"),
     };
 
-    let tag: &str = match tag.as_str_vector() {
-        Some(tags) => tags[0],
-        None => {
-                  eprintln!("ERROR: tag is not a string");
-                  return 
-                },
+    let tag: &str = match tag.as_str() {
+        Some(tag) => tag,
+        None => return argument_error("tag is not a string"),
     };
 
-    let field: &str = match field.as_str_vector() {
-        Some(fields) => fields[0],
-        None => {
-                  eprintln!("ERROR: field is not a string");
-                  return 
-                },
+    let field: &str = match field.as_str() {
+        Some(field) => field,
+        None => return argument_error("field is not a string"),
     };
 
     let mut final_features: Vec<Vec<Vec<u8>>> = Vec::new();
-    let features_unlisted = features.as_list().unwrap();
+    let features_unlisted = match features.as_list() {
+        Some(features) => features,
+        None => return argument_error("features is not a list"),
+    };
     // eprintln!("{:?} tags unlisted length", tags_unlisted.len());
     for (_item_str, item_robj) in features_unlisted {
         let mut int_vec: Vec<Vec<u8>> = Vec::new();
-        let data = item_robj.as_string_vector().unwrap();
+        let data = match item_robj.as_string_vector() {
+            Some(data) => data,
+            None => return argument_error("features is not a list of string vectors"),
+        };
         for element in data {
             let datain: Vec<u8> = element.as_bytes().to_vec();
             int_vec.push(datain);
@@ -162,40 +321,204 @@ fn subsetbam_rust_helper(inputbam: Robj, features: Robj, outputbams: Robj, tag:
     }
     let final_outputbams = match outputbams.as_string_vector() {
         Some(files) => files,
-        None => {
-                  eprintln!("ERROR: outputbams is not a string vector");
-                  return
-                },
+        None => return argument_error("outputbams is not a string vector"),
     };
     let cores = match cores.as_real() {
         Some(n) => n as u64,
-        None => {
-                  eprintln!("ERROR: cores is not an integer");
-                  return
-                },
+        None => return argument_error("cores is not an integer"),
         };
     let mut dump_bam_r: Option<&str> = None;
 
     if Robj::is_na(&dump_bam){
         dump_bam_r = None;
     } else {
-        dump_bam_r = match dump_bam.as_str_vector() {
-            Some(dump) => Some(dump[0]),
-            None => {
-                      eprintln!("ERROR: dump_bam is not a string");
-                      return
-                    },
+        dump_bam_r = match dump_bam.as_str() {
+            Some(dump) => Some(dump),
+            None => return argument_error("dump_bam is not a string"),
         };
     }
 
+    let fasta: Option<String> = match fasta.as_str() {
+        Some("") => None,
+        Some(path) => Some(path.to_string()),
+        None => return argument_error("fasta is not a string"),
+    };
+
+    // with a barcode-to-group file, features and outputs come from its groups and
+    // `outputbams` is the directory they are written to
+    let groups: Option<&str> = match groups.as_str() {
+        Some("") => None,
+        Some(path) => Some(path),
+        None => return argument_error("groups is not a string"),
+    };
+    let (final_features, final_outputbams) = match groups {
+        Some(groups) => {
+            let header = match header.as_bool() {
+                Some(header) => header,
+                None => return argument_error("header is not a logical"),
+            };
+            let template: &str = match template.as_str() {
+                Some(template) => template,
+                None => return argument_error("template is not a string"),
+            };
+            let out_dir: &str = match final_outputbams.as_slice() {
+                [out_dir] => out_dir.as_str(),
+                _ => return argument_error("outputbams is not a single output directory"),
+            };
+            match subsetbam::group_outputs(groups, header, out_dir, template) {
+                Ok(outputs) => outputs,
+                Err(error) => return r_condition(&error),
+            }
+        },
+        None => (final_features, final_outputbams),
+    };
+
+    let max_open_files = match max_open_files.as_real() {
+        Some(n) => n as usize,
+        None => return argument_error("max_open_files is not a number"),
+    };
+
+    let index = match index.as_str() {
+        Some("none") => None,
+        Some("bai") => Some(subsetbam::OutputIndex::Bai),
+        Some("csi") => Some(subsetbam::OutputIndex::Csi),
+        _ => return argument_error("index must be one of none, bai or csi"),
+    };
+
+    // regions to fetch through the index; none means the whole file
+    let regions: Vec<String> = match regions.as_string_vector() {
+        Some(regions) => regions,
+        None if regions.is_null() => Vec::new(),
+        None => return argument_error("regions is not a string vector"),
+    };
+    let bed: Option<&str> = match bed.as_str() {
+        Some("") => None,
+        Some(path) => Some(path),
+        None => return argument_error("bed is not a string"),
+    };
+    let regions = if regions.is_empty() && bed.is_none() {
+        Vec::new()
+    } else {
+        match subsetbam::parse_regions(inputbam, &regions, bed, fasta.as_deref()) {
+            Ok(regions) if regions.is_empty() => return argument_error("no regions to subset"),
+            Ok(regions) => regions,
+            Err(error) => return r_condition(&error),
+        }
+    };
+
     // if cores>1{
     //     subsetbam::subset_bam_rust_split(inputbam, final_features, final_outputbams, final_prefixes, tag, cores, field, dump_bam_r);
     // } else {
     //     subsetbam::subset_bam_rust(inputbam, final_features, final_outputbams, final_prefixes, tag, field, dump_bam_r);
     // }
-    subsetbam::subset_bam(inputbam, final_features, final_outputbams, tag, cores, field, dump_bam_r);
     // subsetbam::subset_bam_rust_parallel(inputbam, final_tags, final_outputbams, final_prefixes, tag, cores);
-    
+    let options = subsetbam::SubsetOptions {
+        tag: tag.to_string(),
+        cores,
+        field: field.to_string(),
+        dump_bam: dump_bam_r.map(String::from),
+        fasta,
+        regions,
+        max_open_files,
+        index,
+    };
+    match subsetbam::subset_bam(inputbam, final_features, final_outputbams.clone(), options) {
+        Ok(metrics) => list!(
+            inputs = list!(
+                input = inputbam,
+                reads = metrics.total_reads as f64,
+                kept = metrics.kept_reads as f64,
+                dumped = metrics.dumped as f64,
+                missing_tag = metrics.missing_tag as f64,
+                multi_assigned = metrics.multi_assigned as f64
+            ),
+            outputs = output_metrics(final_outputbams.into_iter().zip(metrics.written.iter().map(|&reads| reads as u64)))
+        ).into(),
+        Err(error) => r_condition(&error),
+    }
+}
+
+/// splitbam_rust
+/// @export
+/// @keywords internal
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn splitbam_rust_helper(inputbam: Robj, tag: Robj, out_dir: Robj, template: Robj, min_reads: Robj, cores: Robj, fasta: Robj, max_open_files: Robj) -> Robj {
+    let inputbam: &str = match inputbam.as_str() {
+        Some(file) => file,
+        None => return argument_error("inputbam is not a string"),
+    };
+
+    let tag: &str = match tag.as_str() {
+        Some(tag) => tag,
+        None => return argument_error("tag is not a string"),
+    };
+
+    let out_dir: &str = match out_dir.as_str() {
+        Some(dir) => dir,
+        None => return argument_error("out_dir is not a string"),
+    };
+
+    let template: &str = match template.as_str() {
+        Some(template) => template,
+        None => return argument_error("template is not a string"),
+    };
+
+    let min_reads = match min_reads.as_real() {
+        Some(n) => n as usize,
+        None => return argument_error("min_reads is not a number"),
+    };
+
+    let cores = match cores.as_real() {
+        Some(n) => n as u64,
+        None => return argument_error("cores is not an integer"),
+    };
+
+    let fasta: Option<String> = match fasta.as_str() {
+        Some("") => None,
+        Some(path) => Some(path.to_string()),
+        None => return argument_error("fasta is not a string"),
+    };
+
+    let max_open_files = match max_open_files.as_real() {
+        Some(n) => n as usize,
+        None => return argument_error("max_open_files is not a number"),
+    };
+
+    match subsetbam::split_bam(inputbam, tag, out_dir, template, min_reads, cores, fasta.as_deref(), max_open_files) {
+        Ok((metrics, outputs)) => {
+            // values below min_reads have no output, which R turns into NA
+            let values: Vec<String> = outputs.iter().map(|output| output.value.clone()).collect();
+            let paths: Vec<String> = outputs.iter().map(|output| output.path.clone().unwrap_or_default()).collect();
+            let reads: Vec<f64> = outputs.iter().map(|output| output.reads as f64).collect();
+            list!(
+                inputs = list!(
+                    input = inputbam,
+                    reads = metrics.total_reads as f64,
+                    kept = metrics.kept_reads as f64,
+                    missing_tag = metrics.missing_tag as f64
+                ),
+                outputs = list!(value = values, output = paths, reads = reads)
+            ).into()
+        },
+        Err(error) => r_condition(&error),
+    }
+}
+
+/// Builds the R condition for `error`, which the R wrappers signal with `stop()`.
+/// Its classes are the error's own, `mergebamsR_error`, `error` and `condition`,
+/// and a header error also carries the header report.
+fn r_condition(error: &errors::Error) -> Robj {
+    let mut condition: Robj = match error {
+        errors::Error::Header(issues) => list!(message = error.to_string(), call = (), report = header_report(issues)).into(),
+        _ => list!(message = error.to_string(), call = ()).into(),
+    };
+    condition.set_class([error.class(), "mergebamsR_error", "error", "condition"]).unwrap();
+    condition
+}
+
+fn argument_error(message: &str) -> Robj {
+    r_condition(&errors::Error::Argument(message.to_string()))
 }
 
 
@@ -206,6 +529,8 @@ fn subsetbam_rust_helper(inputbam: Robj, features: Robj, outputbams: Robj, tag:
 extendr_module! {
     mod mergebamsR;
     fn mergebams_rust_helper;
+    fn checkheaders_rust_helper;
     fn peekbam_rust_helper;
     fn subsetbam_rust_helper;
+    fn splitbam_rust_helper;
 }
@@ -1,189 +1,1273 @@
-extern crate bam;
-extern crate csv;
-extern crate flate2;
-extern crate itertools;
-
-use itertools::Itertools;
-use differ::{Differ, Tag};
-use bam::RecordWriter;
-use bam::record::tags::TagValue;
+use rust_htslib::bam::{self, Read, Record};
+use rust_htslib::bam::record::Aux;
+use rust_htslib::tpool::ThreadPool;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::path::Path;
 use std::str;
-use std::error::Error;
+use crate::errors::{Error, Result};
+use crate::utils::{get_tag_value, header_field, header_from_lines, header_lines, open_reader, open_writer, sort_order, sub_sort_order, TagValue};
 
 
 #[derive(Clone)]
 struct Params<'a> {
     bams: Vec<&'a str>,
     out_path: &'a str,
-    names: Vec<Option<Vec<String>>>,
-    prefixes: Vec<&'a str>,
+    fail_bam: Option<String>,
+    overwrite: bool,
+    fasta: Option<String>,
+    names: Vec<Option<HashSet<Vec<u8>>>>,
+    rewrites: Vec<TagRewrite>,
     threads: usize,
+    compression_level: u8,
+    order: MergeOrder,
+    reference: ReferenceMode,
+    drop_unmatched: bool,
+    dictionary: Option<Dictionary>,
+    assigned: Vec<Option<ReadGroup>>,
+    read_groups: Vec<ReadGroupEdit>,
+    barcodes: Vec<Option<HashSet<TagValue>>>,
+    barcode_tag: [u8; 2],
+    drop_unlisted: bool,
 }
 
+/// Per-input filters and edits, and merge settings, chosen from R.
+pub struct MergeOptions {
+    pub threads: usize,
+    pub compression_level: u8,
+    pub fail_bam: Option<String>,
+    pub overwrite: bool,
+    pub fasta: Option<String>,
+    pub names: Vec<Option<HashSet<Vec<u8>>>>,
+    pub rewrites: Vec<TagRewrite>,
+    pub sort_order: String,
+    pub reference: String,
+    pub drop_unmatched: bool,
+    pub assigned: Vec<Option<ReadGroup>>,
+    pub barcodes: Vec<Option<HashSet<Vec<u8>>>>,
+    pub barcode_tag: [u8; 2],
+    pub drop_unlisted: bool,
+}
 
-pub fn mergebams_rust<'a> (bams: Vec<&str>, out_path: &'a str, names: Vec<Option<Vec<String>>>, prefixes: Vec<&str>){
-    let params = load_params(bams, names, prefixes, out_path);
-    let _header_result = checkheaders(params.clone());
-    if let Ok((header, params)) = checkheaders(params){
-        let _params = addtags(params, header);
-    }else{
-        eprintln!("ERROR: BAM header sequences do not match - you will need to fix this before merging bams");
+/// A read group assigned to every record of one input.
+#[derive(Clone)]
+pub struct ReadGroup {
+    pub id: String,
+    pub sample: String,
+    pub library: String,
+}
+
+impl ReadGroup {
+    fn header_line(&self) -> String {
+        format!("@RG\tID:{}\tSM:{}\tLB:{}", self.id, self.sample, self.library)
     }
+}
 
+/// The new ID of each read group of one input whose ID was taken (old ID to new ID).
+type RenamedGroups = HashMap<Vec<u8>, Vec<u8>>;
+
+/// How the `RG` tag of the records of one input is set in the output.
+#[derive(Clone)]
+enum ReadGroupEdit {
+    /// Renamed read groups; other tags are left alone.
+    Rename(RenamedGroups),
+    /// Every record gets this ID.
+    Assign(Vec<u8>),
 }
 
+/// How `checkheaders` handles inputs whose `@SQ` names differ.
+#[derive(Clone, Copy, PartialEq)]
+enum ReferenceMode {
+    Strict,
+    Union,
+    Intersection,
+}
 
-fn load_params<'a>(bams: Vec<&'a str>, names: Vec<Option<Vec<String>>>, prefixes: Vec<&'a str>, out_path: &'a str) -> Params<'a> {
-    let threads = 1;
-    
-    Params{
-        bams: bams,
-        out_path: out_path,
-        names: names,
-        prefixes: prefixes,
-        threads: threads,
+/// One `@SQ` line of an input header.
+struct Sequence {
+    name: String,
+    length: Option<String>,
+    checksum: Option<String>,
+    assembly: Option<String>,
+    line: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IssueKind {
+    Order,
+    Name,
+    Length,
+    Checksum,
+    Assembly,
+}
+
+impl IssueKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IssueKind::Order => "order",
+            IssueKind::Name => "name",
+            IssueKind::Length => "length",
+            IssueKind::Checksum => "checksum",
+            IssueKind::Assembly => "assembly",
+        }
+    }
+
+    /// Order and name differences can be reconciled into a merged dictionary;
+    /// a reference with the same name but a different length or checksum cannot.
+    /// Assembly names (e.g. GRCh38 vs hg38) are only reported.
+    fn blocks(&self, mode: ReferenceMode) -> bool {
+        match self {
+            IssueKind::Order | IssueKind::Name => mode == ReferenceMode::Strict,
+            IssueKind::Length | IssueKind::Checksum => true,
+            IssueKind::Assembly => false,
+        }
     }
 }
 
+/// A difference between the `@SQ` lines of `bam` and those of the first input
+/// (for lengths, checksums and assemblies, the first input listing the reference).
+/// `expected` is the value in that input and `found` the value in `bam`;
+/// either is empty when the reference is absent from that header.
+#[derive(Debug)]
+pub struct HeaderIssue {
+    pub bam: String,
+    pub kind: IssueKind,
+    pub reference: String,
+    pub expected: String,
+    pub found: String,
+}
+
+/// Sequence dictionary of the merged output, with the new tid of every
+/// reference of each input (`None` if the dictionary does not contain it).
+#[derive(Clone)]
+struct Dictionary {
+    sq_lines: Vec<String>,
+    tid_maps: Vec<Vec<Option<i32>>>,
+}
+
+impl Dictionary {
+    /// True if every input lists its references in dictionary order, which a
+    /// coordinate merge needs for its output to stay sorted.
+    fn preserves_order(&self) -> bool {
+        self.tid_maps.iter().all(|tid_map| {
+            let tids: Vec<i32> = tid_map.iter().flatten().copied().collect();
+            tids.windows(2).all(|pair| pair[0] < pair[1])
+        })
+    }
+}
+
+/// A tag whose value is rewritten in every record as `prefix + value + suffix`,
+/// with one prefix and suffix per input BAM. `replace_suffix` (e.g. the `-1`
+/// GEM-well suffix) is stripped from the value first; an empty string leaves it alone.
+#[derive(Clone)]
+pub struct TagRewrite {
+    pub tag: [u8; 2],
+    pub prefixes: Vec<String>,
+    pub suffixes: Vec<String>,
+    pub replace_suffix: String,
+}
+
+impl TagRewrite {
+    fn apply(&self, value: &[u8], pos: usize) -> Vec<u8> {
+        let value = value.strip_suffix(self.replace_suffix.as_bytes()).unwrap_or(value);
+        [self.prefixes[pos].as_bytes(), value, self.suffixes[pos].as_bytes()].concat()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum MergeOrder {
+    Unsorted,
+    Coordinate,
+    Queryname,
+}
+
+impl MergeOrder {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MergeOrder::Unsorted => "unsorted",
+            MergeOrder::Coordinate => "coordinate",
+            MergeOrder::Queryname => "queryname",
+        }
+    }
+}
+
+/// Sort key of the next record waiting in each input during a sorted merge.
+enum MergeKey {
+    Coordinate(u32, i64),
+    Queryname(Vec<u8>, u16),
+}
+
+impl Ord for MergeKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (MergeKey::Coordinate(a_tid, a_pos), MergeKey::Coordinate(b_tid, b_pos)) => {
+                (a_tid, a_pos).cmp(&(b_tid, b_pos))
+            },
+            (MergeKey::Queryname(a_name, a_flag), MergeKey::Queryname(b_name, b_flag)) => {
+                strnum_cmp(a_name, b_name).then(a_flag.cmp(b_flag))
+            },
+            _ => Ordering::Equal,
+        }
+    }
+}
+
+impl PartialOrd for MergeKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for MergeKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MergeKey {}
+
+/// Read counts of one input of a merge. Every record read ends up in exactly
+/// one of the counts after `reads`.
+pub struct InputMetrics {
+    pub bam: String,
+    /// Records read from the input.
+    pub reads: u64,
+    /// Records written to the merged output.
+    pub written: u64,
+    /// Records without the first rewritten tag.
+    pub missing_tag: u64,
+    /// Records on a reference missing from the merged dictionary.
+    pub unmatched: u64,
+    /// Records whose barcode is not whitelisted.
+    pub unlisted: u64,
+    /// Records whose name is not among the requested read names.
+    pub filtered: u64,
+    /// Records htslib could not decode (not included in `reads`).
+    pub unreadable: u64,
+    /// Requested read names that no record of the input carries.
+    pub names_not_found: u64,
+}
+
+/// Number of records written to one output of a merge.
+pub struct OutputMetrics {
+    pub path: String,
+    pub reads: u64,
+}
+
+/// What a merge returns to R: the header discrepancies found and the read counts.
+pub struct MergeReport {
+    pub issues: Vec<HeaderIssue>,
+    pub inputs: Vec<InputMetrics>,
+    pub outputs: Vec<OutputMetrics>,
+}
+
+struct Writers {
+    pass_writer: bam::Writer,
+    fail_writer: Option<(bam::Writer, String)>,
+    inputs: Vec<InputMetrics>,
+    fail_written: u64,
+    names_found: Vec<HashSet<Vec<u8>>>,
+}
+
+impl Writers {
+    fn write_fail(&mut self, record: &Record) -> Result<()> {
+        if let Some((fail_writer, fail_bam)) = self.fail_writer.as_mut() {
+            fail_writer.write(record).map_err(|err| Error::output(fail_bam, err))?;
+            self.fail_written+=1;
+        }
+        Ok(())
+    }
+}
+
+
+/// Merges `bams`, returning every header discrepancy found and the read counts of
+/// each input and output. `Error::Header` holds the report when the discrepancies
+/// prevent the merge.
+pub fn mergebams_rust(bams: Vec<&str>, out_path: &str, options: MergeOptions) -> Result<MergeReport>{
+    let params = load_params(bams, out_path, options)?;
+    let (header, params, issues) = checkheaders(params)?;
+    let (inputs, outputs) = addtags(params, header)?;
+    Ok(MergeReport { issues, inputs, outputs })
+}
+
 
-fn checkheaders(params: Params) -> Result<(bam::Header, Params), &'static str>{
-    let bam_it = params.bams.iter().combinations(2);
-    let mut grand_count = 0;
-    for bam_pairs in bam_it {
-        let mut header_vec = Vec::new();
-        for inbam in bam_pairs.iter() {
-            let hreader = bam::BamReader::from_path(inbam, 0).unwrap();
-            let header = hreader.header().clone();
-            header_vec.push(header);
+fn load_params<'a>(bams: Vec<&'a str>, out_path: &'a str, options: MergeOptions) -> Result<Params<'a>> {
+    if bams.is_empty() {
+        return Err(Error::Argument("no BAM files to merge".to_string()));
+    }
+    let per_input = [("names", options.names.len()), ("read groups", options.assigned.len()), ("barcodes", options.barcodes.len())]
+        .into_iter()
+        .chain(options.rewrites.iter().flat_map(|rewrite| [("prefixes", rewrite.prefixes.len()), ("suffixes", rewrite.suffixes.len())]));
+    for (option, len) in per_input {
+        if len != bams.len() {
+            return Err(Error::Argument(format!("{} has {} values for {} BAM files", option, len, bams.len())));
         }
-        let mut count = 0;
-        let a = header_vec[0].reference_names();
-        let b = header_vec[1].reference_names();
-        let differ = Differ::new(&a, &b);
-        for span in differ.spans() {
-            match span.tag {
-                Tag::Equal => (),
-                _ => count+=1,
+    }
+    let detected = detect_order(&bams)?;
+    let order = match options.sort_order.as_str() {
+        "coordinate" => MergeOrder::Coordinate,
+        "queryname" => MergeOrder::Queryname,
+        "unsorted" => MergeOrder::Unsorted,
+        _ => detected,
+    };
+    if order != detected && order != MergeOrder::Unsorted {
+        eprintln!("WARNING: not every input header declares SO:{}; the merge fails at the first read of an input that is not {} sorted\n", order.as_str(), order.as_str());
+    }
+    if order == MergeOrder::Queryname {
+        for inbam in bams.iter() {
+            match sub_sort_order(&read_header(inbam)?) {
+                Some(sub_sort) if sub_sort.starts_with("queryname:") && sub_sort != "queryname:natural" => {
+                    return Err(Error::input(inbam, format!("sorted by SS:{}, but read names are merged in the natural order of samtools sort -n", sub_sort)));
+                },
+                _ => (),
             }
         }
+    }
+    let reference = match options.reference.as_str() {
+        "union" => ReferenceMode::Union,
+        "intersection" => ReferenceMode::Intersection,
+        _ => ReferenceMode::Strict,
+    };
+    Ok(Params{
+        bams,
+        out_path,
+        fail_bam: options.fail_bam,
+        overwrite: options.overwrite,
+        fasta: options.fasta,
+        names: options.names,
+        rewrites: options.rewrites,
+        threads: options.threads,
+        compression_level: options.compression_level,
+        order,
+        reference,
+        drop_unmatched: options.drop_unmatched,
+        dictionary: None,
+        assigned: options.assigned,
+        read_groups: Vec::new(),
+        barcodes: options.barcodes
+            .into_iter()
+            .map(|barcodes| barcodes.map(|barcodes| barcodes.iter().flat_map(|barcode| TagValue::parse_all(barcode)).collect()))
+            .collect(),
+        barcode_tag: options.barcode_tag,
+        drop_unlisted: options.drop_unlisted,
+    })
+}
 
-        eprintln!("Found {} discrepencies between sequence names in the header of:\n{}\nand:\n{}\n", count, bam_pairs[0], bam_pairs[1]);
-        grand_count+=count;
+fn read_header(inbam: &str) -> Result<bam::HeaderView> {
+    let hreader = bam::Reader::from_path(inbam).map_err(|err| Error::input(inbam, err))?;
+    Ok(hreader.header().clone())
+}
+
+fn header_sequences(header: &bam::HeaderView) -> Vec<Sequence> {
+    header_lines(header)
+        .into_iter()
+        .filter(|line| line.starts_with("@SQ"))
+        .filter_map(|line| {
+            let name = header_field(&line, "SN")?.to_string();
+            Some(Sequence {
+                name,
+                length: header_field(&line, "LN").map(String::from),
+                checksum: header_field(&line, "M5").map(|m5| m5.to_lowercase()),
+                assembly: header_field(&line, "AS").map(String::from),
+                line,
+            })
+        })
+        .collect()
+}
+
+/// Returns `line` with the value of `field` replaced by `value`.
+fn set_header_field(line: &str, field: &str, value: &str) -> String {
+    let prefix = format!("{}:", field);
+    line.split('\t')
+        .enumerate()
+        .map(|(i, entry)| if i > 0 && entry.starts_with(&prefix) { format!("{}{}", prefix, value) } else { entry.to_string() })
+        .collect::<Vec<String>>()
+        .join("\t")
+}
+
+/// Appends `-1`, `-2`, ... to `id` until it is no longer taken.
+fn unique_id(id: &str, taken: impl Fn(&str) -> bool) -> String {
+    let mut n = 1;
+    loop {
+        let candidate = format!("{}-{}", id, n);
+        if !taken(&candidate) {
+            return candidate;
+        }
+        n += 1;
     }
-    if grand_count == 0{
-        let new_header = make_new_header(params.bams.clone());
-        return Ok((new_header, params));
+}
+
+fn detect_order(bam_vec: &[&str]) -> Result<MergeOrder> {
+    let mut orders = Vec::new();
+    for inbam in bam_vec.iter() {
+        orders.push(sort_order(&read_header(inbam)?));
+    }
+    if orders.iter().all(|order| order.as_deref() == Some("coordinate")) {
+        Ok(MergeOrder::Coordinate)
+    } else if orders.iter().all(|order| order.as_deref() == Some("queryname")) {
+        Ok(MergeOrder::Queryname)
     } else {
-        return Err("Discrepent headers")
+        Ok(MergeOrder::Unsorted)
     }
-    
 }
 
-fn make_new_header(bam_vec: Vec<&str>) -> bam::Header {
-    // assumes no discrepencies in the headers across bams in bam_vec
-    let hreader = bam::BamReader::from_path(bam_vec[0], 0).unwrap();
-    let mut out_header = hreader.header().clone();
-    let mergebam_line = bam_vec.join(", ");
-    let _msg = out_header.push_line(&("@CO\tmergebams has included the BAM records from the following files (using the header from the first): ".to_owned()+&mergebam_line));
-    return out_header;
+/// Keeps the `@HD` and `@SQ` lines of `lines`, with `@HD SO` set to `order`
+/// (adding an `@HD` line if there is none). When a merged dictionary is given, its `@SQ`
+/// lines replace those of `lines`.
+fn rebuild_header(lines: &[String], order: MergeOrder, dictionary: Option<&Dictionary>) -> Vec<String> {
+    let mut out_header = Vec::new();
+    if !lines.iter().any(|line| line.starts_with("@HD")) {
+        out_header.push(format!("@HD\tVN:1.6\tSO:{}", order.as_str()));
+    }
+    let mut sq_written = false;
+    for line in lines.iter() {
+        if line.starts_with("@HD") {
+            let mut fields: Vec<String> = line.split('\t')
+                .filter(|field| !field.starts_with("SO:") && !field.starts_with("GO:"))
+                .map(String::from)
+                .collect();
+            fields.push(format!("SO:{}", order.as_str()));
+            out_header.push(fields.join("\t"));
+        } else if line.starts_with("@SQ") && dictionary.is_some() {
+            if !sq_written {
+                out_header.extend(dictionary.unwrap().sq_lines.iter().cloned());
+                sq_written = true;
+            }
+        } else if line.starts_with("@SQ") {
+            out_header.push(line.clone());
+        }
+    }
+    out_header
+}
+
+
+fn checkheaders(mut params: Params) -> Result<(bam::Header, Params, Vec<HeaderIssue>)>{
+    let headers: Vec<bam::HeaderView> = params.bams.iter().map(|inbam| read_header(inbam)).collect::<Result<_>>()?;
+    let issues = validate_headers(&params.bams, &headers);
+    if issues.iter().any(|issue| issue.kind.blocks(params.reference)) {
+        return Err(Error::Header(issues))
+    }
+    if issues.iter().any(|issue| issue.kind == IssueKind::Order || issue.kind == IssueKind::Name) {
+        let dictionary = build_dictionary(&params.bams, &headers, params.reference);
+        if params.order == MergeOrder::Coordinate && !dictionary.preserves_order() {
+            eprintln!("WARNING: the inputs list their references in different orders; merging without preserving coordinate order\n");
+            params.order = MergeOrder::Unsorted;
+        }
+        params.dictionary = Some(dictionary);
+    }
+    let (new_header, read_groups) = make_new_header(&params)?;
+    params.read_groups = read_groups;
+    Ok((new_header, params, issues))
 }
 
-fn addtags(params: Params, header: bam::Header) -> Params{
-    let out_path = params.out_path.to_string()+"/out_path.bam";
-    let fail_bam = params.out_path.to_string()+"/fail_bam.bam";
-    let out_path_msg = out_path.clone();
-    let bam_vec = params.bams.clone();
+/// Compares the `@SQ` names and order of every input with those of the first
+/// input, and each reference with the first input listing it.
+pub fn checkheaders_rust(bams: Vec<&str>) -> Result<Vec<HeaderIssue>> {
+    if bams.is_empty() {
+        return Err(Error::Argument("no BAM files to check".to_string()));
+    }
+    let headers: Vec<bam::HeaderView> = bams.iter().map(|inbam| read_header(inbam)).collect::<Result<_>>()?;
+    Ok(validate_headers(&bams, &headers))
+}
+
+fn validate_headers(bam_vec: &[&str], headers: &[bam::HeaderView]) -> Vec<HeaderIssue> {
+    let mut issues = Vec::new();
+    let sequences: Vec<Vec<Sequence>> = headers.iter().map(header_sequences).collect();
+    let first = &sequences[0];
+    // the first line seen for each reference, which later inputs must agree with
+    let mut seen: HashMap<&str, &Sequence> = first.iter().map(|sq| (sq.name.as_str(), sq)).collect();
+    for (inbam, other) in bam_vec.iter().zip(sequences.iter()).skip(1) {
+        let mut issue = |kind: IssueKind, reference: &str, expected: Option<&String>, found: Option<&String>| {
+            issues.push(HeaderIssue {
+                bam: inbam.to_string(),
+                kind,
+                reference: reference.to_string(),
+                expected: expected.cloned().unwrap_or_default(),
+                found: found.cloned().unwrap_or_default(),
+            });
+        };
+        let first_names: HashSet<&str> = first.iter().map(|sq| sq.name.as_str()).collect();
+        let other_names: HashSet<&str> = other.iter().map(|sq| sq.name.as_str()).collect();
+        for sq in first.iter().filter(|sq| !other_names.contains(sq.name.as_str())) {
+            issue(IssueKind::Name, &sq.name, Some(&sq.name), None);
+        }
+        for sq in other.iter().filter(|sq| !first_names.contains(sq.name.as_str())) {
+            issue(IssueKind::Name, &sq.name, None, Some(&sq.name));
+        }
+        let first_shared: Vec<&Sequence> = first.iter().filter(|sq| other_names.contains(sq.name.as_str())).collect();
+        let other_shared: Vec<&Sequence> = other.iter().filter(|sq| first_names.contains(sq.name.as_str())).collect();
+        if let Some((a, b)) = first_shared.iter().zip(other_shared.iter()).find(|(a, b)| a.name != b.name) {
+            issue(IssueKind::Order, &a.name, Some(&a.name), Some(&b.name));
+        }
+        for b in other.iter() {
+            let a = *seen.entry(b.name.as_str()).or_insert(b);
+            if a.length != b.length {
+                issue(IssueKind::Length, &a.name, a.length.as_ref(), b.length.as_ref());
+            }
+            if a.checksum.is_some() && b.checksum.is_some() && a.checksum != b.checksum {
+                issue(IssueKind::Checksum, &a.name, a.checksum.as_ref(), b.checksum.as_ref());
+            }
+            if a.assembly.is_some() && b.assembly.is_some() && a.assembly != b.assembly {
+                issue(IssueKind::Assembly, &a.name, a.assembly.as_ref(), b.assembly.as_ref());
+            }
+        }
+    }
+    issues
+}
+
+/// Builds the union or intersection of the inputs' `@SQ` lines. References are
+/// kept in the order of the first input, with those only found in later inputs
+/// appended in the order they are encountered.
+fn build_dictionary(bam_vec: &[&str], headers: &[bam::HeaderView], mode: ReferenceMode) -> Dictionary {
+    let sequences: Vec<Vec<Sequence>> = headers.iter().map(header_sequences).collect();
+    let mut sq_lines: Vec<String> = Vec::new();
+    let mut index: HashMap<String, i32> = HashMap::new();
+    for (i, input) in sequences.iter().enumerate() {
+        if mode == ReferenceMode::Intersection && i > 0 {
+            break;
+        }
+        for sq in input.iter() {
+            if index.contains_key(&sq.name) {
+                continue;
+            }
+            if mode == ReferenceMode::Intersection
+                && !sequences.iter().all(|other| other.iter().any(|other_sq| other_sq.name == sq.name)) {
+                continue;
+            }
+            index.insert(sq.name.clone(), sq_lines.len() as i32);
+            sq_lines.push(sq.line.clone());
+        }
+    }
+    let tid_maps: Vec<Vec<Option<i32>>> = sequences
+        .iter()
+        .map(|input| input.iter().map(|sq| index.get(&sq.name).copied()).collect())
+        .collect();
+
+    eprintln!("Reconciled sequence dictionary has {} references", sq_lines.len());
+    for (i, tid_map) in tid_maps.iter().enumerate() {
+        let remapped = tid_map.iter().enumerate().filter(|(tid, new_tid)| new_tid.is_some() && **new_tid != Some(*tid as i32)).count();
+        let missing: Vec<&str> = tid_map.iter().zip(sequences[i].iter())
+            .filter(|(new_tid, _)| new_tid.is_none())
+            .map(|(_, sq)| sq.name.as_str())
+            .collect();
+        eprintln!("{}: {} of {} references remapped, {} not in the merged dictionary{}", bam_vec[i], remapped, tid_map.len(), missing.len(),
+            if missing.is_empty() { String::new() } else { format!(" ({})", missing.join(", ")) });
+    }
+    eprintln!();
+    Dictionary { sq_lines, tid_maps }
+}
+
+/// Builds the output header: `@HD` and `@SQ` from the first input (or the merged dictionary),
+/// then the `@RG`, `@PG` and `@CO` lines of every input. An input with an assigned read
+/// group contributes that read group instead of its own. Also returns how the `RG` tag of
+/// each input's records has to change.
+fn make_new_header(params: &Params) -> Result<(bam::Header, Vec<ReadGroupEdit>)> {
+    let mut headers: Vec<Vec<String>> = params.bams.iter().map(|inbam| read_header(inbam).map(|header| header_lines(&header))).collect::<Result<_>>()?;
+    let mut out_header = rebuild_header(&headers[0], params.order, params.dictionary.as_ref());
+    for (lines, assigned) in headers.iter_mut().zip(params.assigned.iter()) {
+        if let Some(read_group) = assigned {
+            lines.retain(|line| !line.starts_with("@RG"));
+            lines.push(read_group.header_line());
+        }
+    }
+    let (rg_lines, renamed_groups) = merge_read_groups(&headers);
+    let pg_lines = merge_programs(&headers, &command_line(params));
+    let mut co_lines: Vec<&String> = Vec::new();
+    for line in headers.iter().flatten().filter(|line| line.starts_with("@CO")) {
+        if !co_lines.contains(&line) {
+            co_lines.push(line);
+        }
+    }
+    out_header.extend(rg_lines.into_iter().chain(pg_lines).chain(co_lines.into_iter().cloned()));
+    let mergebam_line = params.bams.join(", ");
+    out_header.push("@CO\tmergebams has included the BAM records from the following files: ".to_owned()+&mergebam_line);
+    for (inbam, renamed) in params.bams.iter().zip(renamed_groups.iter()) {
+        for (old_id, new_id) in renamed.iter() {
+            eprintln!("Read group {} of {} renamed to {}", String::from_utf8_lossy(old_id), inbam, String::from_utf8_lossy(new_id));
+        }
+    }
+    let read_groups = renamed_groups
+        .into_iter()
+        .zip(params.assigned.iter())
+        .map(|(renamed, assigned)| match assigned {
+            Some(read_group) => {
+                let id = read_group.id.as_bytes().to_vec();
+                ReadGroupEdit::Assign(renamed.get(&id).cloned().unwrap_or(id))
+            },
+            None => ReadGroupEdit::Rename(renamed),
+        })
+        .collect();
+    Ok((header_from_lines(&out_header), read_groups))
+}
+
+/// Unions the `@RG` lines of all inputs. Identical lines are kept once; a line
+/// whose ID is already used by a different read group gets a new ID.
+fn merge_read_groups(headers: &[Vec<String>]) -> (Vec<String>, Vec<RenamedGroups>) {
+    let mut rg_lines: Vec<String> = Vec::new();
+    let mut by_id: HashMap<String, String> = HashMap::new();
+    let mut read_groups = Vec::new();
+    for lines in headers.iter() {
+        let mut renamed: RenamedGroups = HashMap::new();
+        for line in lines.iter().filter(|line| line.starts_with("@RG")) {
+            let id = match header_field(line, "ID") {
+                Some(id) => id,
+                None => continue,
+            };
+            match by_id.get(id) {
+                Some(existing) if existing == line => (),
+                Some(_) => {
+                    let new_id = unique_id(id, |candidate| by_id.contains_key(candidate));
+                    let new_line = set_header_field(line, "ID", &new_id);
+                    renamed.insert(id.as_bytes().to_vec(), new_id.as_bytes().to_vec());
+                    by_id.insert(new_id, new_line.clone());
+                    rg_lines.push(new_line);
+                },
+                None => {
+                    by_id.insert(id.to_string(), line.clone());
+                    rg_lines.push(line.clone());
+                },
+            }
+        }
+        read_groups.push(renamed);
+    }
+    (rg_lines, read_groups)
+}
+
+/// Unions the `@PG` lines of all inputs, renaming colliding IDs (and the `PP`
+/// links that point at them), then appends a mergebamsR `@PG` line to the end of
+/// every chain, as htslib does for multiple chains.
+fn merge_programs(headers: &[Vec<String>], command_line: &str) -> Vec<String> {
+    let mut pg_lines: Vec<String> = Vec::new();
+    let mut by_id: HashMap<String, String> = HashMap::new();
+    for lines in headers.iter() {
+        let programs: Vec<&String> = lines.iter().filter(|line| line.starts_with("@PG")).collect();
+        let mut renamed: HashMap<String, String> = HashMap::new();
+        for line in programs.iter() {
+            if let Some(id) = header_field(line, "ID") {
+                match by_id.get(id) {
+                    Some(existing) if existing == *line => (),
+                    Some(_) => {
+                        let new_id = unique_id(id, |candidate| by_id.contains_key(candidate) || renamed.values().any(|v| v == candidate));
+                        renamed.insert(id.to_string(), new_id);
+                    },
+                    None => (),
+                }
+            }
+        }
+        for line in programs.into_iter() {
+            let id = match header_field(line, "ID") {
+                Some(id) => id,
+                None => continue,
+            };
+            if by_id.get(id) == Some(line) {
+                continue;
+            }
+            let mut new_line = line.clone();
+            if let Some(new_id) = renamed.get(id) {
+                new_line = set_header_field(&new_line, "ID", new_id);
+            }
+            if let Some(new_pp) = header_field(line, "PP").and_then(|pp| renamed.get(pp)) {
+                new_line = set_header_field(&new_line, "PP", new_pp);
+            }
+            let new_id = header_field(&new_line, "ID").unwrap().to_string();
+            by_id.insert(new_id, new_line.clone());
+            pg_lines.push(new_line);
+        }
+    }
+    let parents: HashSet<&str> = pg_lines.iter().filter_map(|line| header_field(line, "PP")).collect();
+    let chain_ends: Vec<String> = pg_lines.iter()
+        .filter_map(|line| header_field(line, "ID"))
+        .filter(|id| !parents.contains(id))
+        .map(String::from)
+        .collect();
+    let mut new_lines: Vec<String> = Vec::new();
+    let mut new_ids: Vec<String> = Vec::new();
+    let pp_values: Vec<Option<String>> = if chain_ends.is_empty() { vec![None] } else { chain_ends.into_iter().map(Some).collect() };
+    for pp in pp_values.into_iter() {
+        let id = if by_id.contains_key("mergebamsR") || new_ids.iter().any(|v| v == "mergebamsR") {
+            unique_id("mergebamsR", |candidate| by_id.contains_key(candidate) || new_ids.iter().any(|v| v == candidate))
+        } else {
+            "mergebamsR".to_string()
+        };
+        let mut line = format!("@PG\tID:{}\tPN:mergebamsR", id);
+        if let Some(pp) = pp {
+            line += &format!("\tPP:{}", pp);
+        }
+        line += &format!("\tVN:{}\tCL:{}", env!("CARGO_PKG_VERSION"), command_line);
+        new_ids.push(id);
+        new_lines.push(line);
+    }
+    pg_lines.extend(new_lines);
+    pg_lines
+}
+
+/// The parameters of this merge, written as the `CL` field of the mergebamsR `@PG` line.
+fn command_line(params: &Params) -> String {
+    fn r_vector<T: AsRef<str>>(values: &[T]) -> String {
+        let quoted: Vec<String> = values.iter().map(|value| format!("\"{}\"", value.as_ref())).collect();
+        format!("c({})", quoted.join(", "))
+    }
+    let tags: Vec<String> = params.rewrites.iter().map(|rewrite| String::from_utf8_lossy(&rewrite.tag).to_string()).collect();
+    let prefixes: Vec<String> = params.rewrites.iter().map(|rewrite| r_vector(&rewrite.prefixes)).collect();
+    let suffixes: Vec<String> = params.rewrites.iter().map(|rewrite| r_vector(&rewrite.suffixes)).collect();
+    let replace_suffix: Vec<&str> = params.rewrites.iter().map(|rewrite| rewrite.replace_suffix.as_str()).collect();
+    let reference = match params.reference {
+        ReferenceMode::Strict => "strict",
+        ReferenceMode::Union => "union",
+        ReferenceMode::Intersection => "intersection",
+    };
+    let args = vec![
+        format!("bams = {}", r_vector(&params.bams)),
+        format!("out_path = \"{}\"", params.out_path),
+        format!("fail_bam = {}", params.fail_bam.as_ref().map_or("FALSE".to_string(), |fail_bam| format!("\"{}\"", fail_bam))),
+        format!("fasta = {}", params.fasta.as_ref().map_or("NULL".to_string(), |fasta| format!("\"{}\"", fasta))),
+        format!("tags = {}", r_vector(&tags)),
+        format!("prefixes = list({})", prefixes.join(", ")),
+        format!("suffixes = list({})", suffixes.join(", ")),
+        format!("replace_suffix = {}", r_vector(&replace_suffix)),
+        format!("sort_order = \"{}\"", params.order.as_str()),
+        format!("reference = \"{}\"", reference),
+        format!("drop_unmatched = {}", if params.drop_unmatched { "TRUE" } else { "FALSE" }),
+        format!("barcode_tag = \"{}\"", String::from_utf8_lossy(&params.barcode_tag)),
+        format!("unlisted = \"{}\"", if params.drop_unlisted { "drop" } else { "fail" }),
+    ];
+    format!(
+        "mergebams({}) with read name filters on {} input(s), barcode whitelists on {} input(s) and read groups assigned to {} input(s)",
+        args.join(", "),
+        params.names.iter().filter(|names| names.is_some()).count(),
+        params.barcodes.iter().filter(|barcodes| barcodes.is_some()).count(),
+        params.assigned.iter().filter(|assigned| assigned.is_some()).count(),
+    )
+}
+
+fn addtags(params: Params, header: bam::Header) -> Result<(Vec<InputMetrics>, Vec<OutputMetrics>)>{
+    check_outputs(&params)?;
     let bam_vec_msg = params.bams.join(" and ");
-    let lab_vec = params.prefixes.clone();
-    let (read_threads, write_threads) = if (*&params.threads as i8) > 2{
-        (((*&params.threads/2) -1) as u16, ((*&params.threads/2) -1) as u16)
+    // every reader and writer shares one htslib pool for BGZF (de)compression
+    let tpool = if params.threads > 1 {
+        let tpool = ThreadPool::new(params.threads as u32)
+            .map_err(|err| Error::Argument(format!("unable to start {} threads: {}", params.threads, err)))?;
+        Some(tpool)
     } else {
-        (0 as u16, 0 as u16)
+        None
     };
-    let mut fail_count = 0;
-    let mut pass_count = 0;
-    let mut other_count = 0;
-    let mut pass_writer = bam::BamWriter::build()
-        .write_header(true)
-        .additional_threads(write_threads)
-        .from_path(out_path, header.clone()).unwrap();
-    let mut fail_writer = bam::BamWriter::build()
-        .write_header(true)
-        .additional_threads(0)
-        .from_path(fail_bam, header.clone()).unwrap();
-    eprintln!("Headers ok\nWriting:\n{}\nfrom:\n{}\n", out_path_msg, bam_vec_msg);
-    for (pos, inbam) in bam_vec.iter().enumerate() {
-        let reader = bam::BamReader::from_path(inbam.to_string(), read_threads).unwrap();
-        let names = &params.names[pos];
-        let mut filter: bool = false;
-        if names.is_some(){
-            filter = true;
-        }
-        for record in reader {
-            match record {
-                Ok(record) => {
-                    if filter{
-                        for name in names.as_ref().unwrap().iter() {
-                            if record.name() == name.as_bytes(){
-                                let preftag: Vec<u8> = lab_vec[pos].as_bytes().to_vec();
-                                let newrecord = edit_record(&record, preftag);
-                                match newrecord {
-                                    Ok(_) => {
-                                        pass_count+=1;
-                                        pass_writer.write(&newrecord.unwrap()).unwrap();
-                                     },
-                                    Err(_) => {
-                                        fail_count+=1;
-                                        fail_writer.write(&record).unwrap();
-                                    },
-                                }
-                            } else {
-                                continue;
-                            }
-                        }
-                    } else {
-                        let preftag: Vec<u8> = lab_vec[pos].as_bytes().to_vec();
-                        let newrecord  = edit_record(&record, preftag);
-                        match newrecord {
-                            Ok(_) => {
-                                        pass_count+=1;
-                                        pass_writer.write(&newrecord.unwrap()).unwrap();
-                                     },
-                            Err(_) => {
-                                fail_count+=1;
-                                fail_writer.write(&record).unwrap();
-                            },
-                        }
-                    }
-                },
-                Err(_) => other_count+=1,
+    let pass_writer = create_output(params.out_path, &header, &params, tpool.as_ref())?;
+    let fail_writer = match params.fail_bam.as_ref() {
+        Some(fail_bam) => Some((create_output(fail_bam, &header, &params, tpool.as_ref())?, fail_bam.clone())),
+        None => None,
+    };
+    let mut writers = Writers {
+        pass_writer,
+        fail_writer,
+        inputs: params.bams.iter().map(|inbam| InputMetrics {
+            bam: inbam.to_string(),
+            reads: 0,
+            written: 0,
+            missing_tag: 0,
+            unmatched: 0,
+            unlisted: 0,
+            filtered: 0,
+            unreadable: 0,
+            names_not_found: 0,
+        }).collect(),
+        fail_written: 0,
+        names_found: vec![HashSet::new(); params.bams.len()],
+    };
+    eprintln!("Headers ok\nWriting:\n{}\nfrom:\n{}\n", params.out_path, bam_vec_msg);
+    match params.order {
+        MergeOrder::Coordinate | MergeOrder::Queryname => {
+            eprintln!("Merging inputs in {} order\n", params.order.as_str());
+            merge_sorted(&params, tpool.as_ref(), &mut writers)?;
+        },
+        MergeOrder::Unsorted => {
+            for (pos, inbam) in params.bams.iter().enumerate() {
+                let mut reader = open_input(inbam, &params, tpool.as_ref())?;
+                while let Some(record) = next_record(&mut reader, pos, &params, &mut writers)? {
+                    process_record(&record, pos, &params, &mut writers)?;
+                }
             }
+        },
+    }
+    for (pos, names) in params.names.iter().enumerate() {
+        if let Some(names) = names {
+            writers.inputs[pos].names_not_found = (names.len() - writers.names_found[pos].len()) as u64;
+        }
+    }
+    let total = |count: fn(&InputMetrics) -> u64| writers.inputs.iter().map(count).sum::<u64>();
+    eprintln!("Processed all reads!!\nFound:\n{} - reads PASSING\n{} - reads PASSING but with issues\n{} - reads FAILING",
+        total(|input| input.written), total(|input| input.unreadable), total(|input| input.missing_tag));
+    if params.dictionary.is_some() {
+        eprintln!("{} - reads on references missing from the merged dictionary ({})", total(|input| input.unmatched),
+            if params.drop_unmatched { "dropped" } else { "written unplaced to the fail BAM" });
+    }
+    if params.barcodes.iter().any(|barcodes| barcodes.is_some()) {
+        eprintln!("{} - reads without a whitelisted {} barcode ({})", total(|input| input.unlisted), String::from_utf8_lossy(&params.barcode_tag),
+            if params.drop_unlisted { "dropped" } else { "written to the fail BAM" });
+    }
+    for (pos, names) in params.names.iter().enumerate() {
+        if let Some(names) = names {
+            eprintln!("{} of {} requested read names were never found in {}", writers.inputs[pos].names_not_found, names.len(), params.bams[pos]);
         }
     }
-    eprintln!("Processed all reads!!\nFound:\n{} - reads PASSING\n{} - reads PASSING but with issues\n{} - reads FAILING", pass_count, other_count, fail_count);
-    return params;
+    let mut outputs = vec![OutputMetrics { path: params.out_path.to_string(), reads: total(|input| input.written) }];
+    if let Some(fail_bam) = params.fail_bam.as_ref() {
+        outputs.push(OutputMetrics { path: fail_bam.clone(), reads: writers.fail_written });
+    }
+    Ok((writers.inputs, outputs))
 }
-    
-fn edit_record(record: &bam::record::Record, preftag: Vec<u8>)-> Result<bam::record::Record, Box<dyn Error>>{
-    let mut newrecord = record.clone();
-    match newrecord.tags().get(b"CB") {
-        Some(TagValue::String(array_view, _)) => {
-            let oldtag = array_view.to_vec();
-            let new_cb = &[preftag, oldtag].concat();
-            newrecord.tags_mut().remove(b"CB");
-            newrecord.tags_mut().push_string(b"CB", &new_cb);
-            Ok(newrecord)
+
+/// Opens a SAM, BAM or CRAM input, decoding CRAM against the reference FASTA if one was given.
+fn open_input(path: &str, params: &Params, tpool: Option<&ThreadPool>) -> Result<bam::Reader> {
+    let mut reader = open_reader(path, params.fasta.as_deref()).map_err(|err| Error::input(path, err))?;
+    if let Some(tpool) = tpool {
+        reader.set_thread_pool(tpool).map_err(|err| Error::input(path, err))?;
+    }
+    Ok(reader)
+}
+
+/// Refuses, before anything is written, an output that already exists (unless
+/// `overwrite` is set), that is also another output, or that is one of the inputs.
+fn check_outputs(params: &Params) -> Result<()> {
+    let outputs: Vec<&str> = std::iter::once(params.out_path).chain(params.fail_bam.as_deref()).collect();
+    for (i, path) in outputs.iter().enumerate() {
+        if !params.overwrite && Path::new(path).exists() {
+            return Err(Error::output(path, "file exists"));
+        }
+        if outputs[..i].iter().any(|other| same_file(other, path)) {
+            return Err(Error::output(path, "fail_bam is the same file as out_path"));
+        }
+        if params.bams.iter().any(|inbam| same_file(inbam, path)) {
+            return Err(Error::output(path, "file is also an input"));
+        }
+    }
+    Ok(())
+}
+
+/// True if `a` and `b` name the same file, whether or not it exists yet.
+fn same_file(a: &str, b: &str) -> bool {
+    let resolve = |path: &str| {
+        let path = Path::new(path);
+        path.canonicalize().ok().or_else(|| {
+            let parent = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            Some(parent.canonicalize().ok()?.join(path.file_name()?))
+        })
+    };
+    match (resolve(a), resolve(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Creates an output (CRAM if `path` ends in `.cram`); `check_outputs` has
+/// already made sure it may be written.
+fn create_output(path: &str, header: &bam::Header, params: &Params, tpool: Option<&ThreadPool>) -> Result<bam::Writer> {
+    let mut writer = open_writer(path, header, params.fasta.as_deref()).map_err(|err| Error::output(path, err))?;
+    writer.set_compression_level(bam::CompressionLevel::Level(params.compression_level as u32)).map_err(|err| Error::output(path, err))?;
+    if let Some(tpool) = tpool {
+        writer.set_thread_pool(tpool).map_err(|err| Error::output(path, err))?;
+    }
+    Ok(writer)
+}
+
+/// Streams every input at once, always writing the record with the smallest
+/// merge key next so that sorted inputs give an output sorted the same way.
+/// Ties are broken by input order, which keeps the merge stable. An input whose
+/// keys go down is an error, as the output header would claim a wrong order.
+fn merge_sorted(params: &Params, tpool: Option<&ThreadPool>, writers: &mut Writers) -> Result<()> {
+    let mut readers = Vec::new();
+    for inbam in params.bams.iter() {
+        readers.push(open_input(inbam, params, tpool)?);
+    }
+    let mut heads: Vec<Option<Record>> = vec![None; readers.len()];
+    let mut heap = BinaryHeap::new();
+    for pos in 0..readers.len() {
+        if let Some(record) = next_record(&mut readers[pos], pos, params, writers)? {
+            heap.push(Reverse((merge_key(&record, params.order), pos)));
+            heads[pos] = Some(record);
+        }
+    }
+    while let Some(Reverse((key, pos))) = heap.pop() {
+        let record = heads[pos].take().unwrap();
+        process_record(&record, pos, params, writers)?;
+        if let Some(next) = next_record(&mut readers[pos], pos, params, writers)? {
+            let next_key = merge_key(&next, params.order);
+            if next_key < key {
+                return Err(Error::input(params.bams[pos], format!("records are not in {} order; merge it with sort_order = \"unsorted\"", params.order.as_str())));
+            }
+            heap.push(Reverse((next_key, pos)));
+            heads[pos] = Some(next);
+        }
+    }
+    Ok(())
+}
+
+/// Unplaced reads (tid -1) sort after every reference, and queryname ties are
+/// broken by the READ1/READ2 flags, as in samtools.
+fn merge_key(record: &Record, order: MergeOrder) -> MergeKey {
+    match order {
+        MergeOrder::Queryname => MergeKey::Queryname(record.qname().to_vec(), record.flags() & 0xc0),
+        _ => {
+            let tid = if record.tid() < 0 { u32::MAX } else { record.tid() as u32 };
+            MergeKey::Coordinate(tid, record.pos())
         },
-        Some(TagValue::Char(value)) => {
-            let oldtag = value.to_string().as_bytes().to_vec();
-            let new_cb = &[preftag, oldtag].concat();
-            newrecord.tags_mut().remove(b"CB");
-            newrecord.tags_mut().push_string(b"CB", &new_cb);
-            Ok(newrecord)
+    }
+}
+
+/// Natural read-name comparison matching samtools' `strnum_cmp`: runs of digits
+/// compare by numeric value (ignoring leading zeros), everything else bytewise.
+fn strnum_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if !a[i].is_ascii_digit() || !b[j].is_ascii_digit() {
+            if a[i] != b[j] {
+                return a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        } else {
+            while i < a.len() && a[i] == b'0' {
+                i += 1;
+            }
+            while j < b.len() && b[j] == b'0' {
+                j += 1;
+            }
+            while i < a.len() && j < b.len() && a[i].is_ascii_digit() && a[i] == b[j] {
+                i += 1;
+                j += 1;
+            }
+            let diff = match (a.get(i), b.get(j)) {
+                (Some(x), Some(y)) => x.cmp(y),
+                (Some(_), None) => Ordering::Greater,
+                (None, Some(_)) => Ordering::Less,
+                (None, None) => Ordering::Equal,
+            };
+            while i < a.len() && j < b.len() && a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+                i += 1;
+                j += 1;
+            }
+            let a_digit = i < a.len() && a[i].is_ascii_digit();
+            let b_digit = j < b.len() && b[j].is_ascii_digit();
+            if a_digit {
+                return Ordering::Greater;
+            } else if b_digit {
+                return Ordering::Less;
+            } else if diff != Ordering::Equal {
+                return diff;
+            }
+        }
+    }
+    (a.len() - i).cmp(&(b.len() - j))
+}
+
+/// Reads the next record of input `pos`, with its read group renamed and moved
+/// onto the merged dictionary if there is one. Reads on references the
+/// dictionary lacks never reach the merge.
+fn next_record(reader: &mut bam::Reader, pos: usize, params: &Params, writers: &mut Writers) -> Result<Option<Record>> {
+    for record in reader.records() {
+        match record {
+            Ok(mut record) => {
+                writers.inputs[pos].reads+=1;
+                edit_read_group(&mut record, &params.read_groups[pos]).map_err(|err| Error::input(params.bams[pos], err))?;
+                let dictionary = match &params.dictionary {
+                    Some(dictionary) => dictionary,
+                    None => return Ok(Some(record)),
+                };
+                if remap_record(&mut record, &dictionary.tid_maps[pos]) {
+                    return Ok(Some(record));
+                }
+                writers.inputs[pos].unmatched+=1;
+                if !params.drop_unmatched {
+                    writers.write_fail(&record)?;
+                }
+            },
+            Err(_) => writers.inputs[pos].unreadable+=1,
+        }
+    }
+    Ok(None)
+}
+
+/// Points the `RG` tag of `record` at its read group's ID in the merged header.
+fn edit_read_group(record: &mut Record, edit: &ReadGroupEdit) -> rust_htslib::errors::Result<()> {
+    let new_id = match edit {
+        ReadGroupEdit::Assign(id) => Some(id.clone()),
+        ReadGroupEdit::Rename(renamed) if renamed.is_empty() => None,
+        ReadGroupEdit::Rename(renamed) => match get_tag_value(record, b"RG") {
+            Some(TagValue::String(id)) => renamed.get(&id).cloned(),
+            _ => None,
         },
-        _ => {
-            Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "'CB' tag not found")))
+    };
+    match new_id {
+        Some(new_id) => set_string_tag(record, b"RG", &new_id),
+        None => Ok(()),
+    }
+}
+
+/// Rewrites the tid and mate tid of `record` through `tid_map`. A mate on a
+/// missing reference becomes unplaced and unmapped (0x8, with TLEN 0); a read
+/// on one becomes unplaced and unmapped (0x4, without CIGAR) and gives false.
+fn remap_record(record: &mut Record, tid_map: &[Option<i32>]) -> bool {
+    let remap = |tid: i32| if tid < 0 { Some(-1) } else { tid_map.get(tid as usize).copied().flatten() };
+    match remap(record.mtid()) {
+        Some(mtid) => record.set_mtid(mtid),
+        None => {
+            record.set_mtid(-1);
+            record.set_mpos(-1);
+            record.set_insert_size(0);
+            record.set_flags((record.flags() | 0x8) & !0x2);
+        },
+    }
+    match remap(record.tid()) {
+        Some(tid) => {
+            record.set_tid(tid);
+            true
+        },
+        None => {
+            let (qname, seq, qual) = (record.qname().to_vec(), record.seq().as_bytes(), record.qual().to_vec());
+            record.set(&qname, None, &seq, &qual);
+            record.set_tid(-1);
+            record.set_pos(-1);
+            // bin of an unplaced read, as `reg2bin(-1, 0)` gives it
+            record.set_bin(4680);
+            record.set_mapq(0);
+            record.set_insert_size(0);
+            record.set_flags((record.flags() | 0x4) & !0x2);
+            false
+        },
+    }
+}
+
+fn process_record(record: &Record, pos: usize, params: &Params, writers: &mut Writers) -> Result<()> {
+    if let Some(names) = &params.names[pos] {
+        if !names.contains(record.qname()) {
+            writers.inputs[pos].filtered+=1;
+            return Ok(());
+        }
+        if !writers.names_found[pos].contains(record.qname()) {
+            writers.names_found[pos].insert(record.qname().to_vec());
         }
     }
+    if let Some(barcodes) = &params.barcodes[pos] {
+        let listed = get_tag_value(record, &params.barcode_tag).is_some_and(|barcode| barcodes.contains(&barcode));
+        if !listed {
+            writers.inputs[pos].unlisted+=1;
+            if !params.drop_unlisted {
+                writers.write_fail(record)?;
+            }
+            return Ok(());
+        }
+    }
+    write_record(record, pos, params, writers)
+}
+
+fn write_record(record: &Record, pos: usize, params: &Params, writers: &mut Writers) -> Result<()> {
+    match edit_record(record, &params.rewrites, pos).map_err(|err| Error::input(params.bams[pos], err))? {
+        Some(newrecord) => {
+            writers.inputs[pos].written+=1;
+            writers.pass_writer.write(&newrecord).map_err(|err| Error::output(params.out_path, err))
+        },
+        None => {
+            writers.inputs[pos].missing_tag+=1;
+            writers.write_fail(record)
+        },
+    }
+}
+    
+/// Applies every rewrite to `record`. Only the first tag is required: a record
+/// without it gives `None` (and goes to the fail BAM), while later tags are
+/// rewritten when present. A numeric tag is rewritten from its text form.
+fn edit_record(record: &Record, rewrites: &[TagRewrite], pos: usize)-> rust_htslib::errors::Result<Option<Record>>{
+    let mut newrecord = record.clone();
+    for (i, rewrite) in rewrites.iter().enumerate() {
+        let oldtag = match get_tag_value(&newrecord, &rewrite.tag) {
+            Some(TagValue::String(value)) => value,
+            Some(value) => value.to_string().into_bytes(),
+            None if i == 0 => return Ok(None),
+            None => continue,
+        };
+        let new_value = rewrite.apply(&oldtag, pos);
+        set_string_tag(&mut newrecord, &rewrite.tag, &new_value)?;
+    }
+    Ok(Some(newrecord))
 }
 
+/// Replaces `tag` of `record` (of whatever type) with a string tag holding `value`.
+fn set_string_tag(record: &mut Record, tag: &[u8], value: &[u8]) -> rust_htslib::errors::Result<()> {
+    let _ = record.remove_aux(tag);
+    record.push_aux(tag, Aux::String(&String::from_utf8_lossy(value)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strnum_cmp() {
+        assert_eq!(strnum_cmp(b"read2", b"read10"), Ordering::Less);
+        assert_eq!(strnum_cmp(b"read10", b"read010"), Ordering::Equal);
+        assert_eq!(strnum_cmp(b"A:1:20", b"A:1:3"), Ordering::Greater);
+        assert_eq!(strnum_cmp(b"A:1", b"A:1:3"), Ordering::Less);
+        assert_eq!(strnum_cmp(b"B1", b"A2"), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_tag_rewrite() {
+        let rewrite = TagRewrite {
+            tag: *b"CB",
+            prefixes: vec!["".to_string(), "s2_".to_string()],
+            suffixes: vec!["-1".to_string(), "-2".to_string()],
+            replace_suffix: "-1".to_string(),
+        };
+        assert_eq!(rewrite.apply(b"ATTGGACAGTCATGCT-1", 0), b"ATTGGACAGTCATGCT-1".to_vec());
+        assert_eq!(rewrite.apply(b"ATTGGACAGTCATGCT-1", 1), b"s2_ATTGGACAGTCATGCT-2".to_vec());
+        assert_eq!(rewrite.apply(b"ATTGGACAGTCATGCT", 1), b"s2_ATTGGACAGTCATGCT-2".to_vec());
+    }
+
+    #[test]
+    fn test_merge_header_lines() {
+        let headers = vec![
+            vec!["@RG\tID:rg1\tSM:a".to_string(), "@PG\tID:bwa\tPN:bwa".to_string(), "@PG\tID:samtools\tPN:samtools\tPP:bwa".to_string()],
+            vec!["@RG\tID:rg1\tSM:b".to_string(), "@PG\tID:bwa\tPN:bwa\tVN:0.7".to_string()],
+        ];
+        let (rg_lines, read_groups) = merge_read_groups(&headers);
+        assert_eq!(rg_lines, vec!["@RG\tID:rg1\tSM:a", "@RG\tID:rg1-1\tSM:b"]);
+        assert!(read_groups[0].is_empty());
+        assert_eq!(read_groups[1].get(b"rg1".as_slice()), Some(&b"rg1-1".to_vec()));
+
+        let pg_lines = merge_programs(&headers, "test");
+        assert_eq!(pg_lines[2], "@PG\tID:bwa-1\tPN:bwa\tVN:0.7");
+        let ends: Vec<Option<&str>> = pg_lines[3..].iter().map(|line| header_field(line, "PP")).collect();
+        assert_eq!(ends, vec![Some("samtools"), Some("bwa-1")]);
+        assert_eq!(header_field(&pg_lines[4], "ID"), Some("mergebamsR-1"));
+    }
+
+    #[test]
+    fn test_same_file() {
+        assert!(same_file("Cargo.toml", "src/../Cargo.toml"));
+        assert!(same_file("out.bam", "./out.bam"));
+        assert!(!same_file("out.bam", "fail.bam"));
+    }
+
+    #[test]
+    fn test_validate_headers() {
+        let header = |lines: &str| bam::HeaderView::from_bytes(lines.as_bytes());
+        let headers = vec![
+            header("@SQ\tSN:chr1\tLN:1000\n"),
+            header("@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chrX\tLN:100\tM5:aa\n"),
+            header("@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chrX\tLN:200\tM5:AB\n"),
+        ];
+        let issues = validate_headers(&["a.bam", "b.bam", "c.bam"], &headers);
+        let found: Vec<(&str, IssueKind, &str, &str)> = issues
+            .iter()
+            .map(|issue| (issue.bam.as_str(), issue.kind, issue.expected.as_str(), issue.found.as_str()))
+            .collect();
+        assert_eq!(found, vec![
+            ("b.bam", IssueKind::Name, "", "chrX"),
+            ("c.bam", IssueKind::Name, "", "chrX"),
+            ("c.bam", IssueKind::Length, "100", "200"),
+            ("c.bam", IssueKind::Checksum, "aa", "ab"),
+        ]);
+        assert!(issues.iter().any(|issue| issue.kind.blocks(ReferenceMode::Union)));
+    }
+
+    #[test]
+    fn test_dictionary_remap() {
+        let header = |names: &[&str]| {
+            let text: String = names.iter().map(|name| format!("@SQ\tSN:{}\tLN:1000\n", name)).collect();
+            bam::HeaderView::from_bytes(text.as_bytes())
+        };
+        let headers = vec![header(&["chr1", "chr2", "chrM"]), header(&["chr2", "chr1", "chrX"])];
+        let bams = vec!["a.bam", "b.bam"];
+
+        let union = build_dictionary(&bams, &headers, ReferenceMode::Union);
+        assert_eq!(union.sq_lines.len(), 4);
+        assert_eq!(union.tid_maps[1], vec![Some(1), Some(0), Some(3)]);
+        assert!(!union.preserves_order());
+        let intersection = build_dictionary(&bams, &headers, ReferenceMode::Intersection);
+        assert_eq!(intersection.sq_lines.len(), 2);
+        assert_eq!(intersection.tid_maps[0], vec![Some(0), Some(1), None]);
+        assert_eq!(intersection.tid_maps[1], vec![Some(1), Some(0), None]);
+
+        let read = |sam: &[u8]| Record::from_sam(&headers[1], sam).unwrap();
+        let mut record = read(b"r1\t147\tchrX\t200\t60\t4M\tchr2\t100\t-104\tACGT\tIIII");
+        assert!(remap_record(&mut record, &union.tid_maps[1]));
+        assert_eq!((record.tid(), record.mtid(), record.flags(), record.insert_size()), (3, 1, 147, -104));
+
+        let mut record = read(b"r1\t147\tchrX\t200\t60\t4M\tchr2\t100\t-104\tACGT\tIIII");
+        assert!(!remap_record(&mut record, &intersection.tid_maps[1]));
+        assert_eq!((record.tid(), record.pos(), record.mtid()), (-1, -1, 1));
+        assert_eq!(record.flags(), 149);
+        assert_eq!(record.cigar_len(), 0);
+        assert_eq!(record.seq().as_bytes(), b"ACGT".to_vec());
+
+        let mut record = read(b"r1\t99\tchr2\t100\t60\t4M\tchrX\t200\t104\tACGT\tIIII");
+        assert!(remap_record(&mut record, &intersection.tid_maps[1]));
+        assert_eq!((record.tid(), record.mtid(), record.mpos()), (1, -1, -1));
+        assert_eq!(record.flags(), 105);
+        assert_eq!(record.insert_size(), 0);
+    }
+
+    fn merge_options(inputs: usize, sort_order: &str) -> MergeOptions {
+        MergeOptions {
+            threads: 1,
+            compression_level: 6,
+            fail_bam: None,
+            overwrite: false,
+            fasta: None,
+            names: vec![None; inputs],
+            rewrites: Vec::new(),
+            sort_order: sort_order.to_string(),
+            reference: "strict".to_string(),
+            drop_unmatched: false,
+            assigned: vec![None; inputs],
+            barcodes: vec![None; inputs],
+            barcode_tag: *b"CB",
+            drop_unlisted: false,
+        }
+    }
+
+    #[test]
+    fn test_merge_order_checked() {
+        let inputbam = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../inst/extdata/test/bam1.bam");
+        let mut reader = bam::Reader::from_path(&inputbam).unwrap();
+        let header = bam::Header::from_template(reader.header());
+        let mut records: Vec<Record> = reader.records().map(|record| record.unwrap()).collect();
+        let tmp = tempfile::tempdir().unwrap();
+        let path = |name: &str| tmp.path().join(name).to_str().unwrap().to_string();
+
+        // still declares SO:coordinate
+        records.reverse();
+        let mut writer = bam::Writer::from_path(path("reversed.bam"), &header, bam::Format::Bam).unwrap();
+        for record in records.iter() {
+            writer.write(record).unwrap();
+        }
+        drop(writer);
+        let reversed = path("reversed.bam");
+        let bams = vec![inputbam.to_str().unwrap(), reversed.as_str()];
+        match mergebams_rust(bams.clone(), &path("coordinate.bam"), merge_options(2, "auto")) {
+            Err(Error::Input { path, .. }) => assert!(path.ends_with("reversed.bam")),
+            _ => panic!("merged an unsorted input in coordinate order"),
+        }
+        assert!(mergebams_rust(bams, &path("unsorted.bam"), merge_options(2, "unsorted")).is_ok());
+
+        let mut lines = header_lines(&bam::HeaderView::from_header(&header));
+        lines[0] = "@HD\tVN:1.6\tSO:queryname\tSS:queryname:lexicographical".to_string();
+        drop(bam::Writer::from_path(path("lexicographical.bam"), &header_from_lines(&lines), bam::Format::Bam).unwrap());
+        let lexicographical = path("lexicographical.bam");
+        assert!(mergebams_rust(vec![&lexicographical, &lexicographical], &path("queryname.bam"), merge_options(2, "auto")).is_err());
+    }
+
+    #[test]
+    fn test_merge_arguments() {
+        let inputbam = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../inst/extdata/test/bam1.bam");
+        let inputbam = inputbam.to_str().unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        let out_path = tmp.path().join("merged.bam");
+        let out_path = out_path.to_str().unwrap();
+        assert!(matches!(checkheaders_rust(Vec::new()), Err(Error::Argument(_))));
+        assert!(matches!(mergebams_rust(Vec::new(), out_path, merge_options(0, "auto")), Err(Error::Argument(_))));
+        assert!(matches!(mergebams_rust(vec![inputbam, inputbam], out_path, merge_options(1, "auto")), Err(Error::Argument(_))));
+    }
+}
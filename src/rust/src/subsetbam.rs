@@ -1,134 +1,536 @@
 
 use std::cmp;
-use failure::Error;
 use rayon::prelude::*;
 use rust_htslib::bam;
-use rust_htslib::bam::record::Aux;
 use rust_htslib::bam::Record;
-use log::{error, info, LevelFilter};
+use log::{info, LevelFilter};
 use simplelog::*;
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::fs;
 use std::io::{self, prelude::*};
 use std::path::{Path, PathBuf};
-use std::process;
+use std::sync::Mutex;
 use tempfile::tempdir;
+use crate::errors::{Error, Result};
+use crate::utils::{get_tag_value, header_from_lines, header_lines, is_cram, open_reader, open_writer, read_barcode_groups, sort_order, TagValue};
 
 pub struct Metrics {
     pub total_reads: usize,
     pub dumped: usize,
     pub kept_reads: usize,
+    pub missing_tag: usize,
+    /// Kept reads written to more than one output.
+    pub multi_assigned: usize,
+    /// Reads written to each output.
+    pub written: Vec<usize>,
 }
 
+impl Metrics {
+    fn new(outputbam_no: usize) -> Metrics {
+        Metrics {
+            total_reads: 0,
+            dumped: 0,
+            kept_reads: 0,
+            missing_tag: 0,
+            multi_assigned: 0,
+            written: vec![0; outputbam_no],
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Args<'a> {
-    cell_barcodes: &'a HashMap<&'a Vec<u8>, usize>,
+    /// The outputs each barcode (or read name) is written to.
+    cell_barcodes: &'a HashMap<TagValue, Vec<usize>>,
     outputbam_no: usize,
+    /// The most files the chunk keeps open at once for its outputs.
+    max_open: usize,
     i: usize,
     bam_file: &'a str,
     tmp_dir: &'a Path,
     bam_tag: String,
     virtual_start: Option<i64>,
     virtual_stop: Option<i64>,
+    /// The regions fetched through the index, in order, instead of the virtual offsets.
+    regions: &'a [Region],
     field: &'a str,
+    /// The final dump BAM; each chunk writes its unassigned reads to its own
+    /// temporary file, merged into this one afterwards.
     dump_bam: Option<&'a str>,
+    fasta: Option<&'a str>,
+    /// In split mode, the output names claimed by the values seen so far.
+    split_names: Option<&'a SplitNames<'a>>,
+}
+
+/// The outputs of `split_bam`, shared by its chunks so that a value whose file
+/// already exists, or whose file name another value has, fails the run as soon
+/// as it is seen rather than after the whole input is read.
+struct SplitNames<'a> {
+    out_dir: &'a Path,
+    template: &'a str,
+    /// The value (as text) of each file name claimed.
+    claimed: Mutex<HashMap<String, String>>,
+}
+
+impl SplitNames<'_> {
+    fn claim(&self, value: &TagValue) -> Result<()> {
+        let value = value.to_string();
+        let name = output_filename(self.template, &value);
+        let mut claimed = self.claimed.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match claimed.get(&name) {
+            Some(other) if *other == value => Ok(()),
+            Some(other) => Err(Error::output(self.out_dir.join(&name).to_string_lossy(), format!("values {} and {} have the same file name", other, value))),
+            None if self.out_dir.join(&name).exists() => Err(Error::output(self.out_dir.join(&name).to_string_lossy(), "output path already exists")),
+            None => {
+                claimed.insert(name, value);
+                Ok(())
+            },
+        }
+    }
 }
 
 
+/// The index built for each coordinate-sorted output: `.bai` or `.csi` for BAM
+/// (`.crai` for CRAM either way).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputIndex {
+    Bai,
+    Csi,
+}
+
+/// A 0-based, half-open interval of sequence `tid` read through the index.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    tid: u32,
+    start: i64,
+    end: i64,
+    /// Reads starting before this position overlap the previous region and were
+    /// already visited there.
+    skip_before: i64,
+}
+
 pub struct Outs {
     metrics: Metrics,
     out_paths: Vec<PathBuf>,
+    /// In split mode, the tag value written to each of `out_paths`.
+    values: Vec<TagValue>,
+    /// The unassigned reads of the chunk, if a dump BAM was asked for.
+    dump_path: Option<PathBuf>,
+}
+
+/// A distinct tag value found by `split_bam`, with the number of reads carrying
+/// it and the output they were written to (`None` if fewer than `min_reads`).
+pub struct SplitOutput {
+    pub value: String,
+    pub path: Option<String>,
+    pub reads: usize,
+}
+
+/// How `subset_bam` reads its input and writes its outputs, chosen from R.
+pub struct SubsetOptions {
+    /// The tag features are matched against, with `field` `"tag"`.
+    pub tag: String,
+    pub cores: u64,
+    /// `"tag"` or `"name"`.
+    pub field: String,
+    pub dump_bam: Option<String>,
+    pub fasta: Option<String>,
+    /// Regions fetched through the index; empty for the whole input.
+    pub regions: Vec<Region>,
+    pub max_open_files: usize,
+    pub index: Option<OutputIndex>,
 }
 
 pub fn subset_bam(
     inputbam: &str,
     final_tags: Vec<Vec<Vec<u8>>>,
     final_outputbams: Vec<String>,
-    tag: &str,
-    cores: u64,
-    field: &str,
-    dump_bam: Option<&str>,
-) {
+    options: SubsetOptions,
+) -> Result<Metrics> {
     let ll = LevelFilter::Info;
-    let bam_tag = tag.to_string();
+    let bam_tag = options.tag.clone();
+    let cores = options.cores;
+    let field = options.field.as_str();
+    let dump_bam = options.dump_bam.as_deref();
+    let fasta = options.fasta.as_deref();
     let outputbam_no = final_outputbams.len();
 
     let _ = SimpleLogger::init(ll, Config::default());
-    check_inputs_exist(inputbam, final_outputbams.clone());
-
-    let tmp_dir = tempdir().unwrap();
-    let virtual_offsets = bgzf_noffsets(inputbam, &cores).unwrap();
+    if final_tags.len() != outputbam_no {
+        return Err(Error::Argument(format!("{} feature sets were given for {} output BAMs", final_tags.len(), outputbam_no)));
+    }
+    check_inputs_exist(inputbam, final_outputbams.iter().cloned().chain(dump_bam.map(String::from)).collect())?;
 
-    let cell_barcodes: HashMap<_, _> = final_tags
-        .iter()
-        .enumerate()
-        .flat_map(|(index, vec)| vec.iter().map(move |value| (value, index)))
-        .collect();
+    let tmp_dir = tempdir().map_err(|err| Error::output("temporary directory", err))?;
 
-    let chunks: Vec<_> = virtual_offsets
-        .iter()
-        .enumerate()
-        .map(|(i, (virtual_start, virtual_stop))| Args {
-            cell_barcodes: &cell_barcodes,
-            outputbam_no,
-            i,
-            bam_file: inputbam,
-            tmp_dir: tmp_dir.path(),
-            bam_tag: bam_tag.clone(),
-            virtual_start: *virtual_start,
-            virtual_stop: *virtual_stop,
-            field,
-            dump_bam,
-        })
-        .collect();
+    // a barcode listed in several features is written to each of their outputs,
+    // and is matched against integer and float tags as well as strings
+    let mut cell_barcodes: HashMap<TagValue, Vec<usize>> = HashMap::new();
+    for (index, vec) in final_tags.iter().enumerate() {
+        for value in vec.iter().flat_map(|value| TagValue::parse_all(value)) {
+            let indices = cell_barcodes.entry(value).or_default();
+            if !indices.contains(&index) {
+                indices.push(index);
+            }
+        }
+    }
 
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(cores as usize)
-        .build()
-        .unwrap();
-    let results: Vec<_> = pool.install(|| {
-        chunks.par_iter().map(|chunk| slice_bam_chunk(chunk)).collect()
-    });
-
-    let mut metrics = Metrics {
-        total_reads: 0,
-        dumped: 0,
-        kept_reads: 0,
+    let args = Args {
+        cell_barcodes: &cell_barcodes,
+        outputbam_no,
+        max_open: chunk_max_open(options.max_open_files, cores, dump_bam.is_some())?,
+        i: 0,
+        bam_file: inputbam,
+        tmp_dir: tmp_dir.path(),
+        bam_tag,
+        virtual_start: None,
+        virtual_stop: None,
+        regions: &options.regions,
+        field,
+        dump_bam,
+        fasta,
+        split_names: None,
     };
+    let results = slice_bam_chunks(&args, cores)?;
+
+    let mut metrics = Metrics::new(outputbam_no);
 
     for c in &results {
         add_metrics(&mut metrics, &c.metrics);
     }
 
     if metrics.kept_reads == 0 {
-        error!("Zero alignments were kept. Does your BAM contain the cell barcodes and/or tag you chose?");
-        process::exit(1);
+        return Err(Error::NoReads("Zero alignments were kept. Does your BAM contain the cell barcodes and/or tag you chose?".to_string()));
     }
 
     let tmp_bams_vec = transpose_vec(
         results
             .iter()
-            .map(|c| &c.out_paths)
-            .collect::<Vec<&Vec<PathBuf>>>(),
+            .map(|c| c.out_paths.as_slice())
+            .collect::<Vec<&[PathBuf]>>(),
     );
 
-    if cores == 1 {
-        for (i, filefrom) in tmp_bams_vec[0].iter().enumerate() {
-            fs::copy(filefrom, &final_outputbams[i]).unwrap();
+    // outputs keep the order of the input, so only an input that is not
+    // coordinate-sorted needs its outputs sorted before they are indexed
+    let sorted_header = match options.index {
+        Some(_) => {
+            use rust_htslib::bam::Read;
+            let header = open_reader(inputbam, fasta).map_err(|err| Error::input(inputbam, err))?.header().clone();
+            match sort_order(&header).as_deref() {
+                Some("coordinate") => None,
+                _ => Some(coordinate_header(&header)),
+            }
+        },
+        None => None,
+    };
+    for (i, tmp_bams) in tmp_bams_vec.into_iter().enumerate() {
+        if let Some(header) = sorted_header.as_ref() {
+            let unsorted = tmp_dir.path().join(format!("unsorted_out{}.bam", i));
+            assemble_output(&tmp_bams, &unsorted.to_string_lossy(), None)?;
+            sort_bam(&unsorted, &final_outputbams[i], header, tmp_dir.path(), fasta, options.max_open_files)?;
+        } else {
+            assemble_output(&tmp_bams, &final_outputbams[i], fasta)?;
         }
-    } else {
-        for (i, tmp_bams) in tmp_bams_vec.into_iter().enumerate() {
-            merge_bams(&tmp_bams, Path::new(&final_outputbams[i]));
+        if let Some(index) = options.index {
+            let index_type = match index {
+                OutputIndex::Bai => bam::index::Type::Bai,
+                OutputIndex::Csi => bam::index::Type::Csi(14),
+            };
+            bam::index::build(&final_outputbams[i], None, index_type, 1).map_err(|err| Error::output(&final_outputbams[i], err))?;
+        }
+    }
+    if let Some(dump_bam) = dump_bam {
+        let tmp_bams: Vec<PathBuf> = results.iter().filter_map(|c| c.dump_path.clone()).collect();
+        assemble_output(&tmp_bams, dump_bam, fasta)?;
+    }
+
+    info!("Done!");
+    info!(
+        "Visited {} alignments, dumped {} and kept {} ({} in more than one output)",
+        metrics.total_reads, metrics.dumped, metrics.kept_reads, metrics.multi_assigned
+    );
+    Ok(metrics)
+}
+
+/// Writes one BAM per distinct value of `tag` into `out_dir`, named by replacing
+/// `{value}` in `template` with the value. Values carried by fewer than
+/// `min_reads` reads are not written. Values that read the same, such as the
+/// integer 5 and the string "5", share an output, as `subset_bam` matches them
+/// alike.
+#[allow(clippy::too_many_arguments)]
+pub fn split_bam(
+    inputbam: &str,
+    tag: &str,
+    out_dir: &str,
+    template: &str,
+    min_reads: usize,
+    cores: u64,
+    fasta: Option<&str>,
+    max_open_files: usize,
+) -> Result<(Metrics, Vec<SplitOutput>)> {
+    let _ = SimpleLogger::init(LevelFilter::Info, Config::default());
+    check_inputs_exist(inputbam, Vec::new())?;
+    if !Path::new(out_dir).is_dir() {
+        return Err(Error::output(out_dir, "output directory does not exist"));
+    }
+    check_template(template)?;
+    let split_names = SplitNames {
+        out_dir: Path::new(out_dir),
+        template,
+        claimed: Mutex::new(HashMap::new()),
+    };
+
+    let tmp_dir = tempdir().map_err(|err| Error::output("temporary directory", err))?;
+    let cell_barcodes = HashMap::new();
+    let args = Args {
+        cell_barcodes: &cell_barcodes,
+        outputbam_no: 0,
+        max_open: chunk_max_open(max_open_files, cores, false)?,
+        i: 0,
+        bam_file: inputbam,
+        tmp_dir: tmp_dir.path(),
+        bam_tag: tag.to_string(),
+        virtual_start: None,
+        virtual_stop: None,
+        regions: &[],
+        field: "split",
+        dump_bam: None,
+        fasta,
+        split_names: Some(&split_names),
+    };
+    let results = slice_bam_chunks(&args, cores)?;
+
+    // the chunk files of each value, in the order the values were first seen
+    let mut metrics = Metrics::new(0);
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let mut values: Vec<(String, Vec<PathBuf>, usize)> = Vec::new();
+    for c in &results {
+        add_metrics(&mut metrics, &c.metrics);
+        for (x, value) in c.values.iter().enumerate() {
+            let value = value.to_string();
+            let pos = *index.entry(value.clone()).or_insert_with(|| {
+                values.push((value, Vec::new(), 0));
+                values.len() - 1
+            });
+            values[pos].1.push(c.out_paths[x].clone());
+            values[pos].2 += c.metrics.written[x];
+        }
+    }
+
+    // every name was claimed while reading, so none exists or is shared yet
+    let mut outputs = Vec::new();
+    for (value, _, reads) in values.iter() {
+        let path = if *reads < min_reads {
+            None
+        } else {
+            Some(Path::new(out_dir).join(output_filename(template, value)).to_string_lossy().to_string())
+        };
+        outputs.push(SplitOutput { value: value.clone(), path, reads: *reads });
+    }
+    metrics.kept_reads = outputs.iter().filter(|output| output.path.is_some()).map(|output| output.reads).sum();
+    metrics.written = outputs.iter().map(|output| output.reads).collect();
+    if metrics.kept_reads == 0 {
+        return Err(Error::NoReads(format!("No value of the {} tag is carried by at least {} reads", tag, min_reads)));
+    }
+
+    for ((_, tmp_bams, _), output) in values.iter().zip(outputs.iter()) {
+        if let Some(path) = output.path.as_ref() {
+            assemble_output(tmp_bams, path, fasta)?;
         }
     }
 
     info!("Done!");
     info!(
-        "Visited {} alignments, dumped {} and kept {}",
-        metrics.total_reads, metrics.dumped, metrics.kept_reads
+        "Visited {} alignments, found {} values of {} and wrote {} reads to {} files",
+        metrics.total_reads, outputs.len(), tag, metrics.kept_reads, outputs.iter().filter(|output| output.path.is_some()).count()
     );
+    Ok((metrics, outputs))
 }
 
-fn transpose_vec<T>(v: Vec<&Vec<T>>) -> Vec<Vec<T>>
+/// The features of each output and the outputs, as `subset_bam` takes them.
+pub type FeatureOutputs = (Vec<Vec<Vec<u8>>>, Vec<String>);
+
+/// Reads the barcode-to-group file `groups_file` into the barcodes of each group
+/// and the output each is written to, `template` in `out_dir` with `{value}`
+/// replaced by the group.
+pub fn group_outputs(
+    groups_file: &str,
+    has_header: bool,
+    out_dir: &str,
+    template: &str,
+) -> Result<FeatureOutputs> {
+    if !Path::new(out_dir).is_dir() {
+        return Err(Error::output(out_dir, "output directory does not exist"));
+    }
+    check_template(template)?;
+    let mut taken = HashSet::new();
+    let mut features = Vec::new();
+    let mut outputbams = Vec::new();
+    for (group, barcodes) in read_barcode_groups(groups_file, has_header)? {
+        let path = Path::new(out_dir).join(output_filename(template, &group)).to_string_lossy().to_string();
+        if !taken.insert(path.clone()) {
+            return Err(Error::output(&path, format!("group {} has the same file name as another group", group)));
+        }
+        features.push(barcodes);
+        outputbams.push(path);
+    }
+    Ok((features, outputbams))
+}
+
+/// Checks that `template` is a file name in which `{value}` can be replaced.
+fn check_template(template: &str) -> Result<()> {
+    if !template.contains("{value}") {
+        return Err(Error::Argument(format!("template {} does not contain {{value}}", template)));
+    }
+    if Path::new(template).file_name() != Some(template.as_ref()) {
+        return Err(Error::Argument(format!("template {} is not a file name", template)));
+    }
+    Ok(())
+}
+
+/// The file name of a split or group output: `template` with `{value}` replaced by
+/// the value, in which anything but letters, digits, `.`, `-` and `_` becomes `_`.
+fn output_filename(template: &str, value: &str) -> String {
+    let value: String = value
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    template.replace("{value}", &value)
+}
+
+/// The files each chunk may keep open for its outputs so that the `cores` chunks
+/// read at once keep at most `max_open_files` open between them. Each chunk also
+/// holds its input reader and, given `dump`, its dump writer, and needs room for
+/// at least one output and one spill with its sidecar.
+fn chunk_max_open(max_open_files: usize, cores: u64, dump: bool) -> Result<usize> {
+    let fixed = 1 + dump as usize;
+    let cores = cmp::max(1, cores as usize);
+    let max_open = (max_open_files / cores).saturating_sub(fixed);
+    if max_open < 3 {
+        return Err(Error::Argument(format!("max_open_files must be at least {} to read with {} cores", (fixed + 3) * cores, cores)));
+    }
+    Ok(max_open)
+}
+
+/// Runs `slice_bam_chunk` on `cores` threads over the chunks of `args.bam_file`,
+/// each chunk getting a copy of `args` with its own index and virtual offsets.
+/// Given `args.regions`, the chunks are instead at most `cores` runs of
+/// consecutive regions, and only those are read.
+fn slice_bam_chunks(args: &Args, cores: u64) -> Result<Vec<Outs>> {
+    let chunks: Vec<_> = if args.regions.is_empty() {
+        // CRAM containers are not BGZF blocks, so a CRAM input is read as one chunk
+        let virtual_offsets = if is_cram(args.bam_file) {
+            vec![(None, None)]
+        } else {
+            bgzf_noffsets(args.bam_file, &cores)?
+        };
+        virtual_offsets
+            .iter()
+            .enumerate()
+            .map(|(i, (virtual_start, virtual_stop))| Args {
+                i,
+                virtual_start: *virtual_start,
+                virtual_stop: *virtual_stop,
+                ..args.clone()
+            })
+            .collect()
+    } else {
+        let per_chunk = args.regions.len().div_ceil(cmp::max(1, cores as usize));
+        args.regions
+            .chunks(per_chunk)
+            .enumerate()
+            .map(|(i, regions)| Args {
+                i,
+                regions,
+                ..args.clone()
+            })
+            .collect()
+    };
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(cores as usize)
+        .build()
+        .map_err(|err| Error::Argument(format!("unable to start {} threads: {}", cores, err)))?;
+    pool.install(|| {
+        chunks.par_iter().map(|chunk| slice_bam_chunk(chunk)).collect::<Result<_>>()
+    })
+}
+
+/// Parses `regions`, as `chr`, `chr:start` or `chr:start-end` with 1-based inclusive
+/// coordinates, and the intervals of the BED file `bed` against the header of
+/// `bam_file`. The result is sorted, with overlapping regions merged, so that
+/// each read is visited once.
+pub fn parse_regions(bam_file: &str, regions: &[String], bed: Option<&str>, fasta: Option<&str>) -> Result<Vec<Region>> {
+    use rust_htslib::bam::Read;
+    let reader = open_reader(bam_file, fasta).map_err(|err| Error::input(bam_file, err))?;
+    let header = reader.header();
+    let tid = |name: &str, region: &str| {
+        header
+            .tid(name.as_bytes())
+            .ok_or_else(|| Error::Argument(format!("region {} names a sequence that is not in {}", region, bam_file)))
+    };
+
+    let mut intervals: Vec<(u32, i64, i64)> = Vec::new();
+    for region in regions.iter() {
+        let region = region.trim();
+        // a whole sequence, whose name may itself contain ':'
+        if let Some(t) = header.tid(region.as_bytes()) {
+            intervals.push((t, 0, header.target_len(t).unwrap_or(0) as i64));
+            continue;
+        }
+        let (name, range) = region
+            .rsplit_once(':')
+            .ok_or_else(|| Error::Argument(format!("region {} names a sequence that is not in {}", region, bam_file)))?;
+        let t = tid(name, region)?;
+        let range = range.replace(',', "");
+        let bad = || Error::Argument(format!("region {} is not of the form chr:start-end", region));
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start.parse::<i64>().map_err(|_| bad())?, end.parse::<i64>().map_err(|_| bad())?),
+            None => (range.parse::<i64>().map_err(|_| bad())?, header.target_len(t).unwrap_or(0) as i64),
+        };
+        if start < 1 || end < start {
+            return Err(bad());
+        }
+        intervals.push((t, start - 1, end));
+    }
+
+    if let Some(bed) = bed {
+        let text = fs::read_to_string(bed).map_err(|err| Error::input(bed, err))?;
+        for (n, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let bad = || Error::input(bed, format!("line {} is not a BED interval", n + 1));
+            if fields.len() < 3 {
+                return Err(bad());
+            }
+            let start = fields[1].parse::<i64>().map_err(|_| bad())?;
+            let end = fields[2].parse::<i64>().map_err(|_| bad())?;
+            if start < 0 || end <= start {
+                return Err(bad());
+            }
+            intervals.push((tid(fields[0], line)?, start, end));
+        }
+    }
+
+    intervals.sort_unstable();
+    let mut merged: Vec<Region> = Vec::new();
+    for (tid, start, end) in intervals.into_iter() {
+        match merged.last_mut() {
+            Some(last) if last.tid == tid && start <= last.end => last.end = cmp::max(last.end, end),
+            Some(last) if last.tid == tid => {
+                let skip_before = last.end;
+                merged.push(Region { tid, start, end, skip_before });
+            },
+            _ => merged.push(Region { tid, start, end, skip_before: 0 }),
+        }
+    }
+    Ok(merged)
+}
+
+fn transpose_vec<T>(v: Vec<&[T]>) -> Vec<Vec<T>>
 where
     T: Clone,
 {
@@ -138,243 +540,716 @@ where
         .collect()
 }
 
-fn check_inputs_exist(bam_file: &str, out_bams_path: Vec<String>) {
+fn check_inputs_exist(bam_file: &str, out_bams_path: Vec<String>) -> Result<()> {
     if !Path::new(bam_file).exists() {
-        error!("File {} does not exist", bam_file);
-        process::exit(1);
+        return Err(Error::input(bam_file, "file does not exist"));
     }
     for out_bam in out_bams_path.iter() {
         let path = Path::new(out_bam);
         if path.exists() {
-            error!("Output path already exists");
-            process::exit(1);
+            return Err(Error::output(out_bam, "output path already exists"));
         }
         if path.is_dir() {
-            error!("Output path is a directory");
-            process::exit(1);
+            return Err(Error::output(out_bam, "output path is a directory"));
         }
-        let parent_dir = path.parent().unwrap();
+        let parent_dir = match path.parent() {
+            Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+            Some(parent) => parent,
+            None => return Err(Error::output(out_bam, "output path is not a file name")),
+        };
         if !parent_dir.exists() {
-            error!("Output directory {:?} does not exist", parent_dir);
-            process::exit(1);
+            return Err(Error::output(out_bam, format!("output directory {:?} does not exist", parent_dir)));
         }
     }
 
-    let extension = Path::new(bam_file).extension().unwrap().to_str().unwrap();
+    let extension = Path::new(bam_file).extension().and_then(|ext| ext.to_str()).unwrap_or("");
     match extension {
         "bam" => {
             let bai = bam_file.to_owned() + ".bai";
             if !Path::new(&bai).exists() {
-                error!("BAM index {} does not exist", bai);
-                process::exit(1);
+                return Err(Error::input(bam_file, format!("BAM index {} does not exist", bai)));
             }
         }
         "cram" => {
             let crai = bam_file.to_owned() + ".crai";
             if !Path::new(&crai).exists() {
-                error!("CRAM index {} does not exist", crai);
-                process::exit(1);
+                return Err(Error::input(bam_file, format!("CRAM index {} does not exist", crai)));
             }
         }
         &_ => {
-            error!("BAM file did not end in .bam or .cram. Unable to validate");
-            process::exit(1);
+            return Err(Error::input(bam_file, "BAM file did not end in .bam or .cram. Unable to validate"));
         }
     }
+    Ok(())
 }
 
-fn get_tag(rec: &Record, bam_tag: &str) -> Option<Vec<u8>> {
-    match rec.aux(bam_tag.as_bytes()) {
-        Ok(Aux::String(hp)) => Some(hp.as_bytes().to_vec()),
-        _ => None,
-    }
-}
-
-fn load_writer(bam: &bam::Reader, out_bam_path: &Path) -> Result<bam::Writer, Error> {
-    use rust_htslib::bam::Read;
-    let hdr = rust_htslib::bam::Header::from_template(bam.header());
-    let out_handle = bam::Writer::from_path(out_bam_path, &hdr, rust_htslib::bam::Format::Bam)?;
+fn load_writer(header: &bam::HeaderView, out_bam_path: &Path, fasta: Option<&str>) -> Result<bam::Writer> {
+    let hdr = rust_htslib::bam::Header::from_template(header);
+    let out_handle = open_writer(out_bam_path, &hdr, fasta).map_err(|err| Error::output(out_bam_path.to_string_lossy(), err))?;
     Ok(out_handle)
 }
 
+/// Splits `bam_path` into at most `num_chunks` ranges of virtual offsets holding
+/// about the same number of records. The boundaries come from the linear index of
+/// the `.bai`, whose entries are offsets at which a record starts, so no record is
+/// split between chunks. Each boundary is the entry closest to its share of the
+/// records, estimated from the mapped and unmapped counts the index keeps for
+/// each reference.
+///
+/// Reads without a coordinate have no window, so they all fall in the last chunk,
+/// and reads piled up in one 16kb window stay together, so chunks are uneven for
+/// an input with many of either. An index without read counts, or a CSI index,
+/// which has no linear index at all, leaves the input read as a single chunk.
 fn bgzf_noffsets(
     bam_path: &str,
     num_chunks: &u64,
-) -> Result<Vec<(Option<i64>, Option<i64>)>, Error> {
-    fn vec_diff(input: &Vec<u64>) -> Vec<u64> {
-        let vals = input.iter();
-        let next_vals = input.iter().skip(1);
-        vals.zip(next_vals).map(|(cur, next)| next - cur).collect()
-    }
-
-    if *num_chunks == 1 {
+) -> Result<Vec<(Option<i64>, Option<i64>)>> {
+    let bai_path = bam_path.to_owned() + ".bai";
+    if *num_chunks == 1 || !Path::new(&bai_path).exists() {
         return Ok(vec![(None, None)]);
     }
 
-    let bam_bytes = fs::metadata(bam_path)?.len();
-    let mut initial_offsets = Vec::new();
-    let step_size = bam_bytes / num_chunks;
-    for n in 1..*num_chunks {
-        initial_offsets.push((step_size * n) as u64);
+    // each window that can start a chunk, with an estimate of the reads before
+    // it: the reads of the references before, and those of its own reference in
+    // proportion to the compressed bytes before the window
+    let (references, unplaced) = read_bai(&bai_path).map_err(|err| Error::input(&bai_path, err))?;
+    let total: u64 = references.iter().map(|reference| reference.reads).sum::<u64>() + unplaced;
+    let target = |n: u64| total * n / num_chunks;
+    let mut windows: Vec<(u64, u64)> = Vec::new();
+    let mut before = 0;
+    for reference in references.iter() {
+        let span = cmp::max(1, (reference.end >> 16).saturating_sub(reference.start >> 16));
+        for &offset in reference.windows.iter() {
+            let bytes = cmp::min(span, (offset >> 16).saturating_sub(reference.start >> 16));
+            let estimate = before + reference.reads * bytes / span;
+            if offset != 0 && estimate != 0 && offset > windows.last().map_or(0, |&(_, last)| last) {
+                windows.push((estimate, offset));
+            }
+        }
+        before += reference.reads;
     }
 
-    let num_bytes = if initial_offsets.len() > 1 {
-        let diff = vec_diff(&initial_offsets);
-        let m = diff.iter().max().unwrap();
-        cmp::min(1 << 16, *m)
-    } else {
-        1 << 16
-    };
-
-    let mut adjusted_offsets = Vec::new();
-    let mut fp = fs::File::open(bam_path)?;
-    for offset in initial_offsets {
-        fp.seek(io::SeekFrom::Start(offset))?;
-        let mut buffer = [0; 2 << 16];
-        fp.read(&mut buffer)?;
-        for i in 0..num_bytes {
-            if is_valid_bgzf_block(&buffer[i as usize..]) {
-                adjusted_offsets.push(offset + i);
-                break;
+    // every target gets the window closest to it, unless an earlier target took it
+    let mut boundaries: Vec<u64> = Vec::new();
+    for n in 1..*num_chunks {
+        let after = windows.partition_point(|&(estimate, _)| estimate < target(n));
+        let closest = [after.checked_sub(1), Some(after)]
+            .into_iter()
+            .flatten()
+            .filter_map(|w| windows.get(w))
+            .min_by_key(|&&(estimate, _)| estimate.abs_diff(target(n)));
+        if let Some(&(_, offset)) = closest {
+            if offset > boundaries.last().copied().unwrap_or(0) {
+                boundaries.push(offset);
             }
         }
     }
 
-    let mut final_offsets = Vec::new();
-    if adjusted_offsets.len() == 1 {
-        final_offsets.push((None, None));
-        return Ok(final_offsets);
-    }
+    let starts = std::iter::once(None).chain(boundaries.iter().map(|&offset| Some(offset as i64)));
+    let stops = boundaries.iter().map(|&offset| Some(offset as i64)).chain(std::iter::once(None));
+    Ok(starts.zip(stops).collect())
+}
 
-    final_offsets.push((None, Some(((adjusted_offsets[1]) as i64) << 16)));
-    for n in 2..num_chunks - 1 {
-        let n = n as usize;
-        final_offsets.push((
-            Some((adjusted_offsets[n - 1] as i64) << 16),
-            Some((adjusted_offsets[n] as i64) << 16),
-        ));
-    }
-    final_offsets.push((
-        Some(((adjusted_offsets[adjusted_offsets.len() - 1]) as i64) << 16),
-        None,
-    ));
-    Ok(final_offsets)
+/// A reference in a BAI index: its number of reads (mapped and unmapped), the
+/// virtual offsets its reads start and end at, and the virtual offset of the
+/// first read in each 16kb window. References without reads are left out.
+struct BaiReference {
+    reads: u64,
+    start: u64,
+    end: u64,
+    windows: Vec<u64>,
 }
 
-fn is_valid_bgzf_block(block: &[u8]) -> bool {
-    if block.len() < 18 {
-        return false;
+/// Reads the references of a BAI index from its pseudo-bins and linear index,
+/// and the number of reads without a coordinate.
+fn read_bai(bai_path: &str) -> io::Result<(Vec<BaiReference>, u64)> {
+    fn read_le<const N: usize>(reader: &mut impl io::Read) -> io::Result<[u8; N]> {
+        let mut buffer = [0; N];
+        reader.read_exact(&mut buffer)?;
+        Ok(buffer)
     }
-    if (block[0] != 31) | (block[1] != 139) | (block[2] != 8) | (block[3] != 4) {
-        return false;
+
+    let mut reader = io::BufReader::new(fs::File::open(bai_path)?);
+    if &read_le::<4>(&mut reader)? != b"BAI\x01" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a BAI index"));
     }
-    true
+    let n_ref = u32::from_le_bytes(read_le(&mut reader)?);
+    let mut references = Vec::new();
+    for _ in 0..n_ref {
+        let mut pseudo_bin = None;
+        let n_bin = u32::from_le_bytes(read_le(&mut reader)?);
+        for _ in 0..n_bin {
+            let bin = u32::from_le_bytes(read_le(&mut reader)?);
+            let n_chunk = u32::from_le_bytes(read_le(&mut reader)?);
+            let chunks = (0..n_chunk * 2)
+                .map(|_| read_le(&mut reader).map(u64::from_le_bytes))
+                .collect::<io::Result<Vec<u64>>>()?;
+            // the pseudo-bin holds the span of the reference and its mapped and unmapped reads
+            if bin == 37450 && n_chunk == 2 {
+                pseudo_bin = Some(chunks);
+            }
+        }
+        let n_intv = u32::from_le_bytes(read_le(&mut reader)?);
+        let windows = (0..n_intv)
+            .map(|_| read_le(&mut reader).map(u64::from_le_bytes))
+            .collect::<io::Result<Vec<u64>>>()?;
+        if let Some(chunks) = pseudo_bin {
+            references.push(BaiReference { reads: chunks[2] + chunks[3], start: chunks[0], end: chunks[1], windows });
+        }
+    }
+    let unplaced = read_le(&mut reader).map(u64::from_le_bytes).unwrap_or(0);
+    Ok((references, unplaced))
 }
 
-fn slice_bam_chunk(args: &Args) -> Outs {
-    let mut bam = bam::Reader::from_path(args.bam_file).unwrap();
-    let mut out_writers: Vec<bam::Writer> = vec![];
-    let tmp_out_bam_files: Vec<_> = (0..args.outputbam_no)
-        .map(|x| args.tmp_dir.join(format!("tmp_chunk{}_out{}.bam", args.i, x)))
+fn slice_bam_chunk(args: &Args) -> Result<Outs> {
+    use rust_htslib::bam::Read;
+    // a chunk of regions is read through the index only, any other chunk through a plain reader
+    let mut plain = None;
+    let mut indexed = None;
+    let header = if args.regions.is_empty() {
+        let bam = open_reader(args.bam_file, args.fasta).map_err(|err| Error::input(args.bam_file, err))?;
+        plain.insert(bam).header().clone()
+    } else {
+        let mut bam = bam::IndexedReader::from_path(args.bam_file).map_err(|err| Error::input(args.bam_file, err))?;
+        if let Some(fasta) = args.fasta {
+            bam.set_reference(fasta).map_err(|err| Error::input(args.bam_file, err))?;
+        }
+        indexed.insert(bam).header().clone()
+    };
+    let mut split_values: HashMap<TagValue, usize> = HashMap::new();
+    // split outputs are only known once their values are seen
+    let outputs = if args.field == "split" { None } else { Some(args.outputbam_no) };
+    let mut out_writers = ChunkWriters::new(args.max_open, bam::Header::from_template(&header), outputs);
+    let mut tmp_out_bam_files: Vec<_> = (0..args.outputbam_no)
+        .map(|x| chunk_path(args, x))
         .collect();
 
-    for tmp_out_bam_file in &tmp_out_bam_files {
-        out_writers.push(load_writer(&bam, Path::new(&tmp_out_bam_file)).unwrap());
+    for x in 0..args.outputbam_no {
+        out_writers.open(args, x)?;
     }
 
-    let mut dump_writer = if let Some(dump_bam_file) = args.dump_bam {
-        Some(load_writer(&bam, Path::new(dump_bam_file)).unwrap())
-    } else {
-        None
+    let dump_path = args.dump_bam.map(|_| args.tmp_dir.join(format!("tmp_chunk{}_dump.bam", args.i)));
+    let mut dump_writer = match dump_path.as_ref() {
+        Some(path) => Some((load_writer(&header, path, args.fasta)?, path.to_string_lossy().to_string())),
+        None => None,
     };
 
-    let mut metrics = Metrics {
-        total_reads: 0,
-        dumped: 0,
-        kept_reads: 0,
-    };
+    let mut metrics = Metrics::new(args.outputbam_no);
 
-    for r in bam.iter_chunk(args.virtual_start, args.virtual_stop) {
-        let rec = r.unwrap();
+    let mut visit = |rec: Record| -> Result<()> {
         metrics.total_reads += 1;
 
         match args.field {
             "name" => {
-                let found = process_name(&rec, args, &mut metrics, &mut out_writers);
-                if !found & dump_writer.is_some() {
-                    metrics.dumped+=1;
-                    let _ = dump_writer.as_mut().unwrap().write(&rec);
+                let found = process_name(&rec, args, &mut metrics, &mut out_writers)?;
+                if !found {
+                    if let Some((writer, path)) = dump_writer.as_mut() {
+                        metrics.dumped+=1;
+                        writer.write(&rec).map_err(|err| Error::output(&*path, err))?;
+                    }
                 }
             }
             "tag" => {
-                let found = process_tag(&rec, args, &mut metrics, &mut out_writers);
-                if !found & dump_writer.is_some() {
-                    metrics.dumped+=1;
-                    let _ = dump_writer.as_mut().unwrap().write(&rec);
+                let found = process_tag(&rec, args, &mut metrics, &mut out_writers)?;
+                if !found {
+                    if let Some((writer, path)) = dump_writer.as_mut() {
+                        metrics.dumped+=1;
+                        writer.write(&rec).map_err(|err| Error::output(&*path, err))?;
+                    }
                 }
             }
+            "split" => {
+                process_split(&rec, args, &mut metrics, &mut out_writers, &mut tmp_out_bam_files, &mut split_values)?;
+            }
             _ => {
-                error!("Invalid field");
-                process::exit(1);
+                return Err(Error::Argument(format!("field '{}' not recognized", args.field)));
+            }
+        }
+        Ok(())
+    };
+
+    if let Some(bam) = plain.as_mut() {
+        for r in bam.iter_chunk(args.virtual_start, args.virtual_stop) {
+            visit(r.map_err(|err| Error::input(args.bam_file, err))?)?;
+        }
+    }
+    if let Some(bam) = indexed.as_mut() {
+        for region in args.regions.iter() {
+            bam.fetch((region.tid, region.start, region.end)).map_err(|err| Error::input(args.bam_file, err))?;
+            for r in bam.records() {
+                let rec = r.map_err(|err| Error::input(args.bam_file, err))?;
+                if rec.pos() < region.skip_before {
+                    continue;
+                }
+                visit(rec)?;
             }
         }
     }
 
-    Outs {
+    out_writers.finish(args, tmp_out_bam_files.len())?;
+
+    let mut values: Vec<_> = split_values.into_iter().collect();
+    values.sort_by_key(|(_, index)| *index);
+    let values = values.into_iter().map(|(value, _)| value).collect();
+
+    Ok(Outs {
         metrics,
         out_paths: tmp_out_bam_files.clone(),
-    }
+        values,
+        dump_path,
+    })
 }
 
 fn process_tag(
     rec: &Record,
     args: &Args,
     metrics: &mut Metrics,
-    out_writers: &mut Vec<bam::Writer>,
-)  -> bool {
-    let barcode = get_tag(&rec, &args.bam_tag).unwrap();
-    if let Some(index) = args.cell_barcodes.get(&barcode) {
-        metrics.kept_reads += 1;
-        out_writers[*index].write(&rec).unwrap();
-        return true
+    out_writers: &mut ChunkWriters,
+)  -> Result<bool> {
+    let barcode = match get_tag_value(rec, args.bam_tag.as_bytes()) {
+        Some(barcode) => barcode,
+        None => {
+            metrics.missing_tag += 1;
+            return Ok(false)
+        },
+    };
+    if let Some(indices) = args.cell_barcodes.get(&barcode) {
+        write_assigned(rec, args, metrics, out_writers, indices)?;
+        Ok(true)
     } else {
-        return false}
+        Ok(false)
+    }
 }
 
 fn process_name(
     rec: &Record,
     args: &Args,
     metrics: &mut Metrics,
-    out_writers: &mut Vec<bam::Writer>,
-)  -> bool {
-    let name = rec.qname().to_vec();
-    if let Some(index) = args.cell_barcodes.get(&name) {
-        metrics.kept_reads += 1;
-        out_writers[*index].write(&rec).unwrap();
-        return true
+    out_writers: &mut ChunkWriters,
+)  -> Result<bool> {
+    let name = TagValue::String(rec.qname().to_vec());
+    if let Some(indices) = args.cell_barcodes.get(&name) {
+        write_assigned(rec, args, metrics, out_writers, indices)?;
+        Ok(true)
     } else {
-        return false;
+        Ok(false)
     }
 }
 
+/// Writes a kept read to each output in `indices`.
+fn write_assigned(
+    rec: &Record,
+    args: &Args,
+    metrics: &mut Metrics,
+    out_writers: &mut ChunkWriters,
+    indices: &[usize],
+) -> Result<()> {
+    metrics.kept_reads += 1;
+    if indices.len() > 1 {
+        metrics.multi_assigned += 1;
+    }
+    for &index in indices.iter() {
+        metrics.written[index] += 1;
+    }
+    out_writers.write(args, rec, indices)
+}
+
+/// Writes `rec` to the chunk file of its tag value, opening one the first time a
+/// value is seen.
+fn process_split(
+    rec: &Record,
+    args: &Args,
+    metrics: &mut Metrics,
+    out_writers: &mut ChunkWriters,
+    tmp_out_bam_files: &mut Vec<PathBuf>,
+    split_values: &mut HashMap<TagValue, usize>,
+) -> Result<bool> {
+    let value = match get_tag_value(rec, args.bam_tag.as_bytes()) {
+        Some(value) => value,
+        None => {
+            metrics.missing_tag += 1;
+            return Ok(false)
+        },
+    };
+    let index = match split_values.get(&value) {
+        Some(index) => *index,
+        None => {
+            if let Some(split_names) = args.split_names {
+                split_names.claim(&value)?;
+            }
+            let index = tmp_out_bam_files.len();
+            out_writers.open(args, index)?;
+            tmp_out_bam_files.push(chunk_path(args, index));
+            metrics.written.push(0);
+            split_values.insert(value, index);
+            index
+        },
+    };
+    metrics.kept_reads += 1;
+    metrics.written[index] += 1;
+    out_writers.write(args, rec, &[index])?;
+    Ok(true)
+}
+
+/// The temporary outputs of a chunk, with at most `max_open` files open at once.
+/// If there may be more outputs than that, the first `direct` outputs are written
+/// directly and the rest in `groups` groups of `group_size`: the reads of each
+/// group go to a spill BAM of its own, with the outputs of each read in a sidecar
+/// file, and `finish` sorts each spill into the outputs of its group, reading it
+/// once for every `max_open - 2` of them. When the number of outputs is not known
+/// up front, groups hold `direct` outputs and the last one takes any beyond them.
+struct ChunkWriters {
+    max_open: usize,
+    direct: usize,
+    groups: usize,
+    group_size: usize,
+    header: bam::Header,
+    writers: Vec<bam::Writer>,
+    spills: Vec<Option<(bam::Writer, io::BufWriter<fs::File>)>>,
+}
 
+impl ChunkWriters {
+    /// `outputs` is the number of outputs of the chunk, if it is known up front.
+    fn new(max_open: usize, header: bam::Header, outputs: Option<usize>) -> ChunkWriters {
+        // half of the files for direct outputs, the other half for spills, which
+        // hold two files open each
+        let direct = match outputs {
+            Some(outputs) if outputs <= max_open => max_open,
+            _ => cmp::max(1, max_open / 2),
+        };
+        let groups = cmp::max(1, max_open.saturating_sub(direct) / 2);
+        let group_size = match outputs {
+            Some(outputs) if outputs > direct => (outputs - direct).div_ceil(groups),
+            _ => direct,
+        };
+        ChunkWriters { max_open, direct, groups, group_size, header, writers: Vec::new(), spills: Vec::new() }
+    }
 
-fn merge_bams(tmp_bams: &Vec<PathBuf>, out_bam_file: &Path) {
+    /// The spill group of output `x`, which is not written directly.
+    fn group(&self, x: usize) -> usize {
+        cmp::min((x - self.direct) / self.group_size, self.groups - 1)
+    }
+
+    /// Opens output `x`, the next output of the chunk, if it is written directly.
+    fn open(&mut self, args: &Args, x: usize) -> Result<()> {
+        if x < self.direct {
+            let path = chunk_path(args, x);
+            self.writers.push(open_writer(&path, &self.header, None).map_err(|err| Error::output(path.to_string_lossy(), err))?);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, args: &Args, rec: &Record, indices: &[usize]) -> Result<()> {
+        let mut spilled: Vec<(usize, u32)> = Vec::new();
+        for &index in indices.iter() {
+            if index < self.direct {
+                self.writers[index].write(rec).map_err(|err| Error::output(chunk_path(args, index).to_string_lossy(), err))?;
+            } else {
+                spilled.push((self.group(index), index as u32));
+            }
+        }
+        spilled.sort_unstable();
+        for (i, &(group, _)) in spilled.iter().enumerate() {
+            if i > 0 && spilled[i - 1].0 == group {
+                continue;
+            }
+            let outputs: Vec<u32> = spilled[i..].iter().take_while(|(g, _)| *g == group).map(|(_, index)| *index).collect();
+            self.spill(args, group, rec, &outputs)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `rec` to the spill of `group`, and the outputs it goes to to its sidecar.
+    fn spill(&mut self, args: &Args, group: usize, rec: &Record, outputs: &[u32]) -> Result<()> {
+        let path = spill_path(args, group);
+        let sidecar_path = path.with_extension("outputs");
+        if self.spills.len() <= group {
+            self.spills.resize_with(group + 1, || None);
+        }
+        if self.spills[group].is_none() {
+            let writer = open_writer(&path, &self.header, None).map_err(|err| Error::output(path.to_string_lossy(), err))?;
+            let sidecar = fs::File::create(&sidecar_path).map_err(|err| Error::output(sidecar_path.to_string_lossy(), err))?;
+            self.spills[group] = Some((writer, io::BufWriter::new(sidecar)));
+        }
+        let (writer, sidecar) = self.spills[group].as_mut().unwrap();
+        writer.write(rec).map_err(|err| Error::output(path.to_string_lossy(), err))?;
+        let sidecar_error = |err: io::Error| Error::output(sidecar_path.to_string_lossy(), err);
+        sidecar.write_all(&(outputs.len() as u32).to_le_bytes()).map_err(sidecar_error)?;
+        for index in outputs.iter() {
+            sidecar.write_all(&index.to_le_bytes()).map_err(sidecar_error)?;
+        }
+        Ok(())
+    }
+
+    /// Closes the direct outputs and the spills, then writes outputs
+    /// `direct..outputs` from the spill of their group, as many at a time as
+    /// the spill and its sidecar leave room for.
+    fn finish(self, args: &Args, outputs: usize) -> Result<()> {
+        // unnamed, so that it does not shadow `std::io::Read` for the sidecar
+        use rust_htslib::bam::Read as _;
+        let ChunkWriters { max_open, direct, groups, group_size, header, writers, spills } = self;
+        drop(writers);
+
+        let mut spilled = Vec::new();
+        for (group, spill) in spills.into_iter().enumerate() {
+            spilled.push(spill.is_some());
+            if let Some((writer, mut sidecar)) = spill {
+                drop(writer);
+                let sidecar_path = spill_path(args, group).with_extension("outputs");
+                sidecar.flush().map_err(|err| Error::output(sidecar_path.to_string_lossy(), err))?;
+            }
+        }
+
+        let batch = cmp::max(1, max_open.saturating_sub(2));
+        let mut batches = Vec::new();
+        for group in 0..groups {
+            let start = cmp::min(direct + group * group_size, outputs);
+            let end = if group + 1 == groups { outputs } else { cmp::min(start + group_size, outputs) };
+            for first in (start..end).step_by(batch) {
+                batches.push((group, first, cmp::min(first + batch, end)));
+            }
+        }
+        for (group, first, last) in batches.into_iter() {
+            let mut writers = (first..last)
+                .map(|x| {
+                    let out_path = chunk_path(args, x);
+                    open_writer(&out_path, &header, None).map_err(|err| Error::output(out_path.to_string_lossy(), err))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            if !spilled.get(group).copied().unwrap_or(false) {
+                continue;
+            }
+
+            let path = spill_path(args, group);
+            let sidecar_path = path.with_extension("outputs");
+            let sidecar_error = |err: io::Error| Error::input(sidecar_path.to_string_lossy(), err);
+            let mut reader = bam::Reader::from_path(&path).map_err(|err| Error::input(path.to_string_lossy(), err))?;
+            let mut sidecar = io::BufReader::new(fs::File::open(&sidecar_path).map_err(sidecar_error)?);
+            let mut word = [0; 4];
+            for rec in reader.records() {
+                let rec = rec.map_err(|err| Error::input(path.to_string_lossy(), err))?;
+                sidecar.read_exact(&mut word).map_err(sidecar_error)?;
+                for _ in 0..u32::from_le_bytes(word) {
+                    sidecar.read_exact(&mut word).map_err(sidecar_error)?;
+                    let index = u32::from_le_bytes(word) as usize;
+                    if (first..last).contains(&index) {
+                        writers[index - first].write(&rec).map_err(|err| Error::output(chunk_path(args, index).to_string_lossy(), err))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The temporary file holding the reads of chunk `args.i` for the outputs of
+/// spill group `group`.
+fn spill_path(args: &Args, group: usize) -> PathBuf {
+    args.tmp_dir.join(format!("tmp_chunk{}_spill{}.bam", args.i, group))
+}
+
+/// The temporary file receiving output `x` of chunk `args.i`.
+fn chunk_path(args: &Args, x: usize) -> PathBuf {
+    args.tmp_dir.join(format!("tmp_chunk{}_out{}.bam", args.i, x))
+}
+
+
+
+/// `header` with `@HD SO` set to `coordinate`, adding an `@HD` line if there is none.
+fn coordinate_header(header: &bam::HeaderView) -> bam::Header {
+    let mut lines = header_lines(header);
+    match lines.iter_mut().find(|line| line.starts_with("@HD")) {
+        Some(hd) => {
+            let fields: Vec<&str> = hd.split('\t').filter(|field| !field.starts_with("SO:") && !field.starts_with("GO:")).collect();
+            *hd = format!("{}\tSO:coordinate", fields.join("\t"));
+        },
+        None => lines.insert(0, "@HD\tVN:1.6\tSO:coordinate".to_string()),
+    }
+    header_from_lines(&lines)
+}
+
+/// Reads sorted in memory at a time by `sort_bam`.
+const SORT_RUN_READS: usize = 500_000;
+
+/// The coordinate sort key of `rec`, placing reads without a reference last.
+fn coordinate_key(rec: &Record) -> (u32, i64) {
+    if rec.tid() < 0 {
+        (u32::MAX, i64::MAX)
+    } else {
+        (rec.tid() as u32, rec.pos())
+    }
+}
+
+/// Writes the reads of `unsorted` to `out_bam` with `header`, sorted by coordinate.
+/// Runs of `SORT_RUN_READS` reads are sorted in memory and written to `tmp_dir`,
+/// then merged, at most `max_open_files - 1` at a time so that the merge and its
+/// output stay within `max_open_files`; reads at the same position keep their order.
+fn sort_bam(unsorted: &Path, out_bam: &str, header: &bam::Header, tmp_dir: &Path, fasta: Option<&str>, max_open_files: usize) -> Result<()> {
+    use rust_htslib::bam::Read;
+    let unsorted_name = unsorted.to_string_lossy();
+    let stem = unsorted.file_stem().unwrap_or_default().to_string_lossy();
+    let mut reader = bam::Reader::from_path(unsorted).map_err(|err| Error::input(&unsorted_name, err))?;
+    let mut runs: Vec<PathBuf> = Vec::new();
+    let mut batch: Vec<Record> = Vec::with_capacity(SORT_RUN_READS);
+    let mut records = reader.records();
+    loop {
+        let rec = records.next().transpose().map_err(|err| Error::input(&unsorted_name, err))?;
+        let done = rec.is_none();
+        if let Some(rec) = rec {
+            batch.push(rec);
+        }
+        if batch.len() == SORT_RUN_READS || (done && !batch.is_empty()) {
+            batch.sort_by_key(coordinate_key);
+            let path = tmp_dir.join(format!("{}_run{}.bam", stem, runs.len()));
+            let mut writer = open_writer(&path, header, None).map_err(|err| Error::output(path.to_string_lossy(), err))?;
+            for rec in batch.drain(..) {
+                writer.write(&rec).map_err(|err| Error::output(path.to_string_lossy(), err))?;
+            }
+            runs.push(path);
+        }
+        if done {
+            break;
+        }
+    }
+
+    // consecutive runs are merged together, so that ties still keep their order
+    let fan_in = cmp::max(2, max_open_files.saturating_sub(1));
+    let mut pass = 0;
+    while runs.len() > fan_in {
+        pass += 1;
+        let mut merged = Vec::new();
+        for group in runs.chunks(fan_in) {
+            let path = tmp_dir.join(format!("{}_pass{}_run{}.bam", stem, pass, merged.len()));
+            let path_name = path.to_string_lossy().to_string();
+            let mut writer = open_writer(&path, header, None).map_err(|err| Error::output(&path_name, err))?;
+            merge_runs(group, &mut writer, &path_name)?;
+            for run in group.iter() {
+                fs::remove_file(run).map_err(|err| Error::output(run.to_string_lossy(), err))?;
+            }
+            merged.push(path);
+        }
+        runs = merged;
+    }
+
+    let mut out = open_writer(out_bam, header, fasta).map_err(|err| Error::output(out_bam, err))?;
+    merge_runs(&runs, &mut out, out_bam)
+}
+
+/// Merges the coordinate-sorted `runs` into `out`, taking the read of the earliest
+/// run first when several are at the same position.
+fn merge_runs(runs: &[PathBuf], out: &mut bam::Writer, out_name: &str) -> Result<()> {
+    use rust_htslib::bam::Read;
+    let mut readers = runs
+        .iter()
+        .map(|path| bam::Reader::from_path(path).map_err(|err| Error::input(path.to_string_lossy(), err)))
+        .collect::<Result<Vec<_>>>()?;
+    let mut next: Vec<Option<Record>> = Vec::new();
+    let mut heap = BinaryHeap::new();
+    for (run, reader) in readers.iter_mut().enumerate() {
+        let rec = reader.records().next().transpose().map_err(|err| Error::input(runs[run].to_string_lossy(), err))?;
+        if let Some(rec) = rec.as_ref() {
+            heap.push(Reverse((coordinate_key(rec), run)));
+        }
+        next.push(rec);
+    }
+    while let Some(Reverse((_, run))) = heap.pop() {
+        let rec = next[run].take().unwrap();
+        out.write(&rec).map_err(|err| Error::output(out_name, err))?;
+        next[run] = readers[run].records().next().transpose().map_err(|err| Error::input(runs[run].to_string_lossy(), err))?;
+        if let Some(rec) = next[run].as_ref() {
+            heap.push(Reverse((coordinate_key(rec), run)));
+        }
+    }
+    Ok(())
+}
+
+/// Writes the chunk files `tmp_bams`, in order, to the final output `out_bam`.
+/// A BAM output is assembled from the compressed blocks of the chunks; the
+/// records are only decoded and recompressed for CRAM or when that fails.
+fn assemble_output(tmp_bams: &[PathBuf], out_bam: &str, fasta: Option<&str>) -> Result<()> {
+    if tmp_bams.len() == 1 && !is_cram(out_bam) {
+        fs::copy(&tmp_bams[0], out_bam).map_err(|err| Error::output(out_bam, err))?;
+    } else if is_cram(out_bam) || !concat_bams(tmp_bams, out_bam)? {
+        merge_bams(tmp_bams, Path::new(out_bam), fasta)?;
+    }
+    Ok(())
+}
+
+/// The BGZF end-of-file marker, an empty block.
+const BGZF_EOF: [u8; 28] = [31, 139, 8, 4, 0, 0, 0, 0, 0, 255, 6, 0, 66, 67, 2, 0, 27, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// Concatenates the BGZF blocks of BAM chunks, like `samtools cat`: the header
+/// blocks of the first chunk, the record blocks of every chunk without its EOF
+/// marker, and one EOF marker. htslib flushes the block after writing a header,
+/// so records start on a block boundary; if they do not, or the headers of the
+/// chunks differ, nothing is written and false is returned.
+fn concat_bams(tmp_bams: &[PathBuf], out_bam: &str) -> Result<bool> {
+    // unnamed, so that it does not shadow `std::io::Read` for the chunk files
+    use rust_htslib::bam::Read as _;
+    let mut header: Option<Vec<u8>> = None;
+    let mut starts = Vec::new();
+    for b in tmp_bams.iter() {
+        let reader = bam::Reader::from_path(b).map_err(|err| Error::input(b.to_string_lossy(), err))?;
+        let text = reader.header().as_bytes().to_vec();
+        match header.as_ref() {
+            Some(first) if *first != text => return Ok(false),
+            Some(_) => {},
+            None => header = Some(text),
+        }
+        let start = reader.tell();
+        if start & 0xffff != 0 {
+            return Ok(false);
+        }
+        starts.push((start >> 16) as u64);
+    }
+
+    let output_error = |err: io::Error| Error::output(out_bam, err);
+    let mut out = io::BufWriter::new(fs::File::create(out_bam).map_err(output_error)?);
+    for (i, (b, start)) in tmp_bams.iter().zip(starts).enumerate() {
+        let input_error = |err: io::Error| Error::input(b.to_string_lossy(), err);
+        let mut fp = fs::File::open(b).map_err(input_error)?;
+        let mut end = fp.metadata().map_err(input_error)?.len();
+        if end >= BGZF_EOF.len() as u64 {
+            let mut tail = [0; 28];
+            fp.seek(io::SeekFrom::Start(end - tail.len() as u64)).map_err(input_error)?;
+            fp.read_exact(&mut tail).map_err(input_error)?;
+            if tail == BGZF_EOF {
+                end -= tail.len() as u64;
+            }
+        }
+        // the first chunk keeps its header blocks
+        let start = if i == 0 { 0 } else { start };
+        fp.seek(io::SeekFrom::Start(start)).map_err(input_error)?;
+        io::copy(&mut fp.take(end.saturating_sub(start)), &mut out).map_err(output_error)?;
+    }
+    out.write_all(&BGZF_EOF).map_err(output_error)?;
+    out.flush().map_err(output_error)?;
+    Ok(true)
+}
+
+fn merge_bams(tmp_bams: &[PathBuf], out_bam_file: &Path, fasta: Option<&str>) -> Result<()> {
     use bam::Read;
-    let bam = bam::Reader::from_path(tmp_bams[0].to_str().unwrap()).unwrap();
-    let mut out_bam = load_writer(&bam, out_bam_file).unwrap();
+    let bam = bam::Reader::from_path(&tmp_bams[0]).map_err(|err| Error::input(tmp_bams[0].to_string_lossy(), err))?;
+    let mut out_bam = load_writer(bam.header(), out_bam_file, fasta)?;
     for b in tmp_bams.iter() {
-        let mut rdr = bam::Reader::from_path(b).unwrap();
+        let mut rdr = bam::Reader::from_path(b).map_err(|err| Error::input(b.to_string_lossy(), err))?;
         for rec in rdr.records() {
-            out_bam.write(&rec.unwrap()).unwrap();
+            let rec = rec.map_err(|err| Error::input(b.to_string_lossy(), err))?;
+            out_bam.write(&rec).map_err(|err| Error::output(out_bam_file.to_string_lossy(), err))?;
         }
     }
+    Ok(())
 }
 
 fn add_metrics(metrics: &mut Metrics, m: &Metrics) {
     metrics.total_reads += m.total_reads;
     metrics.dumped += m.dumped;
     metrics.kept_reads += m.kept_reads;
+    metrics.missing_tag += m.missing_tag;
+    metrics.multi_assigned += m.multi_assigned;
+    for (written, w) in metrics.written.iter_mut().zip(m.written.iter()) {
+        *written += w;
+    }
 }
 
 
@@ -405,7 +1280,7 @@ mod tests {
     /// Compute digest value for given `Reader` and print it
     /// This is taken from the Rust cookbook
     /// https://rust-lang-nursery.github.io/rust-cookbook/cryptography/hashing.html
-    fn sha256_digest<R: Read>(mut reader: R) -> Result<Digest, Error> {
+    fn sha256_digest<R: Read>(mut reader: R) -> io::Result<Digest> {
         let mut context = Context::new(&SHA256);
         let mut buffer = [0; 1024];
 
@@ -420,6 +1295,19 @@ mod tests {
         Ok(context.finish())
     }
 
+    fn subset_options(cores: u64, max_open_files: usize, index: Option<OutputIndex>) -> SubsetOptions {
+        SubsetOptions {
+            tag: "CB".to_string(),
+            cores,
+            field: "tag".to_string(),
+            dump_bam: None,
+            fasta: None,
+            regions: Vec::new(),
+            max_open_files,
+            index,
+        }
+    }
+
     #[test]
     fn test_bam_single_core() {
         let final_tags = vec![vec![b"ATTGGACAGTCATGCT-1".to_vec(), b"ATCATGGCAGACGCTC-1".to_vec()]];
@@ -430,8 +1318,7 @@ mod tests {
         // let final_outputbams =  Path::new(&root).join("test/out/subset.bam").to_str().unwrap().to_string();
         let final_outputbams1 =  Path::new(&root).join("test/out/subset1_sc.bam").to_str().unwrap().to_string();
         // let final_outputbams2 =  Path::new(&root).join("test/out/subset2.bam").to_str().unwrap().to_string();
-        let tag = "CB";
-        subset_bam(&inputbam, final_tags, vec![final_outputbams1.clone()], tag, 1, "tag", None);
+        subset_bam(&inputbam, final_tags, vec![final_outputbams1.clone()], subset_options(1, 1000, None)).unwrap();
         let fh = fs::File::open(Path::new(&final_outputbams1)).unwrap();
         let d = sha256_digest(fh).unwrap();
         let d = HEXUPPER.encode(d.as_ref());
@@ -443,34 +1330,345 @@ mod tests {
         fs::remove_dir_all(out_dir).unwrap();   
     }
 
+    fn read_records(path: &Path) -> Vec<Record> {
+        use rust_htslib::bam::Read as _;
+        let mut reader = bam::Reader::from_path(path).unwrap();
+        reader.records().map(|rec| rec.unwrap()).collect()
+    }
+
     #[test]
     fn test_bam_multiple_core() {
+        // chunk outputs are concatenated block by block, so the bytes depend on
+        // the number of cores but the records must not
         let final_tags = vec![vec![b"ATTGGACAGTCATGCT-1".to_vec(), b"ATCATGGCAGACGCTC-1".to_vec()],
                                                 vec![ b"GGAAAGCTCTCAACTT-1".to_vec(), b"GAGCAGACAGACAGGT-1".to_vec()]];
         let root = get_library_location();
-        let out_dir = Path::new(&root).join("test/out");
-        fs::create_dir(&out_dir).unwrap();
+        let out_dir = tempdir().unwrap();
         let inputbam =  Path::new(&root).join("test/bam1.bam").to_str().unwrap().to_string();
-        let final_outputbams1 =  Path::new(&root).join("test/out/subset1.bam").to_str().unwrap().to_string();
-        let final_outputbams2 =  Path::new(&root).join("test/out/subset2.bam").to_str().unwrap().to_string();
-        let tag = "CB";
-        subset_bam(&inputbam, final_tags, vec![final_outputbams1.clone(), final_outputbams2.clone()], tag, 8, "tag", None);
-        let fh = fs::File::open(Path::new(&final_outputbams2)).unwrap();
-        let d = sha256_digest(fh).unwrap();
-        let d = HEXUPPER.encode(d.as_ref());
-        // eprint!("SHA256: {}\n", d);
-        assert_eq!(
-            d,
-            "536FC3AEEF2B007AAAB85B7E29F8E6BDCBEAF7A8F1D14C9A0EC849FABCDC1E1D"
-        );
-        let fh = fs::File::open(Path::new(&final_outputbams1)).unwrap();
-        let d = sha256_digest(fh).unwrap();
-        let d = HEXUPPER.encode(d.as_ref());
-        assert_eq!(
-            d,
-            "43F97A078D9860A4D083019919B17CDD05102C71467B223DF201E03B28AB14AD"
-        );
-        fs::remove_dir_all(out_dir).unwrap();  
+        let outputs = |prefix: &str| -> Vec<String> {
+            (1..=2).map(|x| out_dir.path().join(format!("{}{}.bam", prefix, x)).to_str().unwrap().to_string()).collect()
+        };
+        subset_bam(&inputbam, final_tags.clone(), outputs("single"), subset_options(1, 1000, None)).unwrap();
+        subset_bam(&inputbam, final_tags, outputs("multiple"), subset_options(8, 1000, None)).unwrap();
+        for (single, multiple) in outputs("single").iter().zip(outputs("multiple").iter()) {
+            let expected = read_records(Path::new(single));
+            assert!(!expected.is_empty());
+            assert!(read_records(Path::new(multiple)) == expected);
+        }
+    }
+
+    #[test]
+    fn test_check_inputs_exist() {
+        let root = get_library_location();
+        let inputbam = Path::new(&root).join("test/bam1.bam").to_str().unwrap().to_string();
+        assert!(check_inputs_exist(&inputbam, vec!["subset_in_working_dir.bam".to_string()]).is_ok());
+        assert!(check_inputs_exist(&inputbam, vec!["".to_string()]).is_err());
+        assert!(check_inputs_exist(&inputbam, vec!["/".to_string()]).is_err());
+        let final_tags = vec![vec![b"ATTGGACAGTCATGCT-1".to_vec()]];
+        assert!(matches!(subset_bam(&inputbam, final_tags, Vec::new(), subset_options(1, 1000, None)), Err(Error::Argument(_))));
+    }
+
+    #[test]
+    fn test_sorted_index() {
+        use rust_htslib::bam::Read as _;
+        let final_tags = vec![vec![b"ATTGGACAGTCATGCT-1".to_vec(), b"ATCATGGCAGACGCTC-1".to_vec()]];
+        let root = get_library_location();
+        let inputbam = Path::new(&root).join("test/bam1.bam");
+        let tmp = tempdir().unwrap();
+        let expected = {
+            let out_bam = tmp.path().join("expected.bam").to_str().unwrap().to_string();
+            subset_bam(inputbam.to_str().unwrap(), final_tags.clone(), vec![out_bam.clone()], subset_options(1, 1000, None)).unwrap();
+            read_records(Path::new(&out_bam))
+        };
+        let key = |rec: &Record| (rec.qname().to_vec(), rec.tid(), rec.pos(), rec.flags());
+
+        // the same reads under a header that does not claim to be sorted
+        let reader = bam::Reader::from_path(&inputbam).unwrap();
+        let mut lines = header_lines(reader.header());
+        lines[0] = "@HD\tVN:1.4".to_string();
+        let header = header_from_lines(&lines);
+        let undeclared = tmp.path().join("undeclared.bam");
+        let mut writer = bam::Writer::from_path(&undeclared, &header, bam::Format::Bam).unwrap();
+        let all = read_records(&inputbam);
+        for rec in all.iter() {
+            writer.write(rec).unwrap();
+        }
+        drop(writer);
+        bam::index::build(&undeclared, None, bam::index::Type::Bai, 1).unwrap();
+
+        let out_bam = tmp.path().join("sorted.bam").to_str().unwrap().to_string();
+        subset_bam(undeclared.to_str().unwrap(), final_tags, vec![out_bam.clone()], subset_options(2, 1000, Some(OutputIndex::Bai))).unwrap();
+        let sorted = read_records(Path::new(&out_bam));
+        assert!(sorted.iter().map(key).eq(expected.iter().map(key)));
+        let mut indexed = bam::IndexedReader::from_path(&out_bam).unwrap();
+        assert_eq!(header_lines(indexed.header())[0], "@HD\tVN:1.4\tSO:coordinate");
+        let tid = sorted[0].tid();
+        indexed.fetch(tid as u32).unwrap();
+        let fetched: Vec<_> = indexed.records().map(|rec| key(&rec.unwrap())).collect();
+        assert!(!fetched.is_empty());
+        assert_eq!(fetched, sorted.iter().filter(|rec| rec.tid() == tid).map(key).collect::<Vec<_>>());
+
+        // reads in reverse are put back in order, unplaced ones last; reads at the
+        // same position keep their input order, so they stay reversed
+        let reversed = tmp.path().join("reversed.bam");
+        let mut writer = bam::Writer::from_path(&reversed, &header, bam::Format::Bam).unwrap();
+        for rec in all.iter().rev() {
+            writer.write(rec).unwrap();
+        }
+        drop(writer);
+        let resorted = tmp.path().join("resorted.bam");
+        sort_bam(&reversed, resorted.to_str().unwrap(), &coordinate_header(&bam::HeaderView::from_header(&header)), tmp.path(), None, 3).unwrap();
+        let resorted = read_records(&resorted);
+        assert!(resorted.iter().map(coordinate_key).eq(all.iter().map(coordinate_key)));
+        let mut keys: Vec<_> = resorted.iter().map(key).collect();
+        let mut all_keys: Vec<_> = all.iter().map(key).collect();
+        keys.sort();
+        all_keys.sort();
+        assert_eq!(keys, all_keys);
+    }
+
+    #[test]
+    fn test_spilled_outputs() {
+        // one output per barcode, and one more for all of them
+        let barcodes = ["ATTGGACAGTCATGCT-1", "TTTACTGAGTCGATAA-1", "ATCATGGCAGACGCTC-1", "CACTCCATCTCGCTTG-1", "GCTGCGAGTCCGTCAG-1", "CTCTACGGTCCAGTAT-1",
+                        "TAGTTGGGTTCAGACT-1", "CAGTCCTAGCAATATG-1", "CGACCTTTCCACGTGG-1", "GGAAAGCTCTCAACTT-1", "GGACATTCAGCAGTTT-1", "GACGCGTCATCTCGCT-1",
+                        "TCGGGACGTGCTCTTC-1", "GTTCATTTCGAATCCA-1", "GTGCAGCGTACACCGC-1", "CAGTCCTTCACGGTTA-1", "GAGCAGACAGACAGGT-1", "ATGTGTGCACATGTGT-1"];
+        let mut final_tags: Vec<Vec<Vec<u8>>> = barcodes.iter().map(|barcode| vec![barcode.as_bytes().to_vec()]).collect();
+        final_tags.push(barcodes.iter().map(|barcode| barcode.as_bytes().to_vec()).collect());
+        let root = get_library_location();
+        let out_dir = tempdir().unwrap();
+        let inputbam =  Path::new(&root).join("test/bam1.bam").to_str().unwrap().to_string();
+        let outputs = |prefix: &str| -> Vec<String> {
+            (0..final_tags.len()).map(|x| out_dir.path().join(format!("{}{}.bam", prefix, x)).to_str().unwrap().to_string()).collect()
+        };
+        subset_bam(&inputbam, final_tags.clone(), outputs("open"), subset_options(1, 1000, None)).unwrap();
+        // 10 files for each chunk besides its reader: 5 direct outputs and 2 spill
+        // groups of 7; then 5 files, 2 direct outputs and one spill group of 17,
+        // which is read 3 outputs at a time
+        for (cores, max_open_files) in [(1, 11), (2, 22), (1, 6)] {
+            let prefix = format!("spilled{}_{}_", cores, max_open_files);
+            subset_bam(&inputbam, final_tags.clone(), outputs(&prefix), subset_options(cores, max_open_files, None)).unwrap();
+            for (open, spilled) in outputs("open").iter().zip(outputs(&prefix).iter()) {
+                assert!(read_records(Path::new(spilled)) == read_records(Path::new(open)), "{} cores: {}", cores, spilled);
+            }
+        }
+        assert_eq!(chunk_max_open(4, 1, false).unwrap(), 3);
+        assert!(chunk_max_open(3, 1, false).is_err());
+        assert!(chunk_max_open(9, 2, true).is_err());
+    }
+
+    /// Writes the first `values.len()` reads of `inputbam` to an indexed BAM, each
+    /// with a `ZV` tag of its value.
+    fn tagged_bam(inputbam: &Path, values: Vec<bam::record::Aux>, path: &Path) {
+        use rust_htslib::bam::Read as _;
+        let mut reader = bam::Reader::from_path(inputbam).unwrap();
+        let header = bam::Header::from_template(reader.header());
+        let mut writer = bam::Writer::from_path(path, &header, bam::Format::Bam).unwrap();
+        for (rec, value) in reader.records().zip(values) {
+            let mut rec = rec.unwrap();
+            rec.push_aux(b"ZV", value).unwrap();
+            writer.write(&rec).unwrap();
+        }
+        drop(writer);
+        bam::index::build(path, None, bam::index::Type::Bai, 1).unwrap();
+    }
+
+    #[test]
+    fn test_split_bam() {
+        use rust_htslib::bam::record::Aux;
+        let root = get_library_location();
+        let inputbam = Path::new(&root).join("test/bam1.bam");
+        let tmp = tempdir().unwrap();
+        let out_dir = tmp.path().join("split");
+        fs::create_dir(&out_dir).unwrap();
+        let out_dir = out_dir.to_str().unwrap();
+
+        let (metrics, outputs) = split_bam(inputbam.to_str().unwrap(), "CB", out_dir, "cell_{value}.bam", 10, 2, None, 1000).unwrap();
+        let written: Vec<&SplitOutput> = outputs.iter().filter(|output| output.path.is_some()).collect();
+        assert!(!written.is_empty() && written.len() < outputs.len());
+        assert_eq!(metrics.kept_reads, written.iter().map(|output| output.reads).sum::<usize>());
+        assert_eq!(outputs.iter().map(|output| output.reads).sum::<usize>() + metrics.missing_tag, metrics.total_reads);
+        for output in outputs.iter() {
+            let path = Path::new(out_dir).join(output_filename("cell_{value}.bam", &output.value));
+            assert_eq!(output.path.is_some(), output.reads >= 10);
+            assert_eq!(path.exists(), output.reads >= 10);
+            if path.exists() {
+                let records = read_records(&path);
+                assert_eq!(records.len(), output.reads);
+                assert!(records.iter().all(|rec| get_tag_value(rec, b"CB").unwrap().to_string() == output.value));
+            }
+        }
+        // the outputs of the first run are now in the way
+        match split_bam(inputbam.to_str().unwrap(), "CB", out_dir, "cell_{value}.bam", 10, 2, None, 1000) {
+            Err(Error::Output { path, .. }) => assert!(path.starts_with(out_dir)),
+            _ => panic!("overwrote an existing output"),
+        }
+        assert!(matches!(split_bam(inputbam.to_str().unwrap(), "CB", out_dir, "cell.bam", 1, 1, None, 1000), Err(Error::Argument(_))));
+        assert!(matches!(split_bam(inputbam.to_str().unwrap(), "CB", out_dir, "cells/{value}.bam", 1, 1, None, 1000), Err(Error::Argument(_))));
+
+        // an integer and a string that read the same share an output
+        let typed = tmp.path().join("typed.bam");
+        tagged_bam(&inputbam, vec![Aux::I32(5), Aux::String("5"), Aux::String("x y"), Aux::I8(5)], &typed);
+        let typed_dir = tmp.path().join("typed");
+        fs::create_dir(&typed_dir).unwrap();
+        let (_, outputs) = split_bam(typed.to_str().unwrap(), "ZV", typed_dir.to_str().unwrap(), "{value}.bam", 1, 1, None, 1000).unwrap();
+        let outputs: Vec<(&str, usize)> = outputs.iter().map(|output| (output.value.as_str(), output.reads)).collect();
+        assert_eq!(outputs, vec![("5", 3), ("x y", 1)]);
+        assert_eq!(read_records(&typed_dir.join("5.bam")).len(), 3);
+        assert!(typed_dir.join("x_y.bam").exists());
+
+        // but two strings that only differ in characters the file name drops do not
+        let colliding = tmp.path().join("colliding.bam");
+        tagged_bam(&inputbam, vec![Aux::String("x y"), Aux::String("x_y")], &colliding);
+        let colliding_dir = tmp.path().join("colliding");
+        fs::create_dir(&colliding_dir).unwrap();
+        match split_bam(colliding.to_str().unwrap(), "ZV", colliding_dir.to_str().unwrap(), "{value}.bam", 1, 1, None, 1000) {
+            Err(Error::Output { message, .. }) => assert!(message.contains("same file name")),
+            _ => panic!("wrote two values to one file"),
+        }
+    }
+
+    #[test]
+    fn test_output_filename() {
+        assert_eq!(output_filename("{value}.bam", "ACGT-1"), "ACGT-1.bam");
+        assert_eq!(output_filename("cell_{value}.cram", "a b/c:1.5"), "cell_a_b_c_1.5.cram");
+        assert_eq!(output_filename("{value}_{value}.bam", "é"), "___.bam");
+        assert!(check_template("{value}.bam").is_ok());
+        assert!(check_template("cells.bam").is_err());
+        assert!(check_template("../{value}.bam").is_err());
+    }
+
+    #[test]
+    fn test_group_outputs() {
+        use flate2::{write::GzEncoder, Compression};
+        let tmp = tempdir().unwrap();
+        let path = |name: &str| tmp.path().join(name).to_str().unwrap().to_string();
+        let barcodes = |values: &[&str]| -> Vec<Vec<u8>> { values.iter().map(|value| value.as_bytes().to_vec()).collect() };
+        let expected = vec![
+            ("T cell".to_string(), barcodes(&["AAA-1", "CCC-1"])),
+            ("B".to_string(), barcodes(&["GGG-1"])),
+        ];
+
+        fs::write(path("groups.csv"), "barcode,group\nAAA-1,T cell\nGGG-1, B\nCCC-1,T cell,extra\n").unwrap();
+        assert_eq!(read_barcode_groups(&path("groups.csv"), true).unwrap(), expected);
+        fs::write(path("groups.tsv"), "AAA-1\tT cell\nGGG-1\tB\nCCC-1\tT cell\n").unwrap();
+        assert_eq!(read_barcode_groups(&path("groups.tsv"), false).unwrap(), expected);
+        let mut gz = GzEncoder::new(fs::File::create(path("groups.csv.gz")).unwrap(), Compression::default());
+        gz.write_all(b"AAA-1,T cell\nGGG-1,B\nCCC-1,T cell\n").unwrap();
+        gz.finish().unwrap();
+        assert_eq!(read_barcode_groups(&path("groups.csv.gz"), false).unwrap(), expected);
+        // without a header its first row is a barcode
+        assert_eq!(read_barcode_groups(&path("groups.csv"), false).unwrap()[0], ("group".to_string(), barcodes(&["barcode"])));
+
+        fs::write(path("short.csv"), "AAA-1,T cell\nGGG-1\n").unwrap();
+        match read_barcode_groups(&path("short.csv"), false) {
+            Err(Error::Input { message, .. }) => assert!(message.starts_with("line 2 ")),
+            _ => panic!("read a row without a group"),
+        }
+        fs::write(path("empty.csv"), "barcode,group\n").unwrap();
+        assert!(read_barcode_groups(&path("empty.csv"), true).is_err());
+
+        let (features, outputbams) = group_outputs(&path("groups.tsv"), false, tmp.path().to_str().unwrap(), "{value}.bam").unwrap();
+        assert_eq!(features, vec![barcodes(&["AAA-1", "CCC-1"]), barcodes(&["GGG-1"])]);
+        assert_eq!(outputbams, vec![path("T_cell.bam"), path("B.bam")]);
+        fs::write(path("colliding.csv"), "AAA-1,T cell\nGGG-1,T_cell\n").unwrap();
+        assert!(matches!(group_outputs(&path("colliding.csv"), false, tmp.path().to_str().unwrap(), "{value}.bam"), Err(Error::Output { .. })));
+        assert!(group_outputs(&path("groups.tsv"), false, &path("missing"), "{value}.bam").is_err());
+    }
+
+    #[test]
+    fn test_concat_bams() {
+        use flate2::read::MultiGzDecoder;
+        use rust_htslib::bam::Read as _;
+        let root = get_library_location();
+        let inputbam =  Path::new(&root).join("test/bam1.bam");
+        let reader = bam::Reader::from_path(&inputbam).unwrap();
+        let header = bam::Header::from_template(reader.header());
+        let all = read_records(&inputbam);
+
+        let tmp = tempdir().unwrap();
+        let chunks: Vec<PathBuf> = all
+            .chunks(all.len() / 3 + 1)
+            .enumerate()
+            .map(|(i, records)| {
+                let path = tmp.path().join(format!("chunk{}.bam", i));
+                let mut writer = bam::Writer::from_path(&path, &header, bam::Format::Bam).unwrap();
+                for rec in records.iter() {
+                    writer.write(rec).unwrap();
+                }
+                path
+            })
+            .collect();
+        let out_bam = tmp.path().join("concat.bam");
+        assert!(concat_bams(&chunks, out_bam.to_str().unwrap()).unwrap());
+
+        let bytes = fs::read(&out_bam).unwrap();
+        assert!(bytes.ends_with(&BGZF_EOF));
+        assert_eq!(bytes.windows(BGZF_EOF.len()).filter(|window| *window == BGZF_EOF).count(), 1);
+        let mut text = Vec::new();
+        MultiGzDecoder::new(&bytes[..]).read_to_end(&mut text).unwrap();
+        assert_eq!(text.windows(4).filter(|window| *window == b"BAM\x01").count(), 1);
+
+        let first = bam::Reader::from_path(&chunks[0]).unwrap();
+        let reader = bam::Reader::from_path(&out_bam).unwrap();
+        assert_eq!(reader.header().as_bytes(), first.header().as_bytes());
+        assert!(read_records(&out_bam) == all);
+    }
+
+    #[test]
+    fn test_parse_regions() {
+        let root = get_library_location();
+        let inputbam = Path::new(&root).join("test/bam1.bam").to_str().unwrap().to_string();
+        let tmp = tempdir().unwrap();
+        let bed = tmp.path().join("regions.bed");
+        fs::write(&bed, "# 0-based\n10\t99\t200\n10\t150\t300\n").unwrap();
+        let regions: Vec<String> = ["MT", "1:1,000-2,000", "1:1500-3000", "2:5000", "1:10000-20000"].iter().map(|r| r.to_string()).collect();
+        let parsed = parse_regions(&inputbam, &regions, Some(bed.to_str().unwrap()), None).unwrap();
+        assert_eq!(parsed, vec![
+            Region { tid: 0, start: 999, end: 3000, skip_before: 0 },
+            Region { tid: 0, start: 9999, end: 20000, skip_before: 3000 },
+            Region { tid: 1, start: 99, end: 300, skip_before: 0 },
+            Region { tid: 11, start: 4999, end: 242193529, skip_before: 0 },
+            Region { tid: 22, start: 0, end: 16569, skip_before: 0 },
+        ]);
+        assert!(parse_regions(&inputbam, &["chr1:1-10".to_string()], None, None).is_err());
+        assert!(parse_regions(&inputbam, &["1:20-10".to_string()], None, None).is_err());
+    }
+
+    #[test]
+    fn test_bgzf_noffsets() {
+        use rust_htslib::bam::Read as _;
+        let root = get_library_location();
+        let key = |rec: Record| (rec.qname().to_vec(), rec.tid(), rec.pos(), rec.flags());
+        for name in ["bam1.bam", "bam2.bam"] {
+            let inputbam = Path::new(&root).join("test").join(name).to_str().unwrap().to_string();
+            let mut reader = bam::Reader::from_path(&inputbam).unwrap();
+            let all: Vec<_> = reader.records().map(|rec| key(rec.unwrap())).collect();
+
+            for cores in [1, 2, 3, 4, 8] {
+                let chunks = bgzf_noffsets(&inputbam, &cores).unwrap();
+                assert!(!chunks.is_empty() && chunks.len() as u64 <= cores);
+                let boundaries: Vec<i64> = chunks.iter().filter_map(|(_, stop)| *stop).collect();
+                assert!(boundaries.windows(2).all(|pair| pair[0] < pair[1]));
+
+                let mut reader = bam::Reader::from_path(&inputbam).unwrap();
+                let mut records = Vec::new();
+                let mut sizes = Vec::new();
+                for (start, stop) in chunks.iter() {
+                    let before = records.len();
+                    records.extend(reader.iter_chunk(*start, *stop).map(|rec| key(rec.unwrap())));
+                    sizes.push(records.len() - before);
+                }
+                assert_eq!(records, all, "{} {} cores", name, cores);
+                // with more cores, reads piled up in one 16kb window or without a
+                // coordinate leave nowhere to place a boundary
+                if cores <= 4 {
+                    let even = all.len() / cores as usize;
+                    assert_eq!(sizes.len() as u64, cores, "{} {} cores", name, cores);
+                    assert!(sizes.iter().all(|&size| size * 4 > even * 3 && size * 4 < even * 5), "{} {} cores: {:?}", name, cores, sizes);
+                }
+            }
+        }
     }
 
     #[test]
@@ -511,7 +1709,7 @@ mod tests {
     // let ans = cells.get("AAGTCTGCACAGGAGT-1");
     // assert_eq!(ans.unwrap(), "3");
     let mut cells = HashMap::new();
-    let tags = vec![vec!["ATTGGACAGTCATGCT-1", "TTTACTGAGTCGATAA-1", "ATCATGGCAGACGCTC-1", "CACTCCATCTCGCTTG-1", "GCTGCGAGTCCGTCAG-1", "CTCTACGGTCCAGTAT-1", "TAGTTGGGTTCAGACT-1", "CAGTCCTAGCAATATG-1"],
+    let tags = [vec!["ATTGGACAGTCATGCT-1", "TTTACTGAGTCGATAA-1", "ATCATGGCAGACGCTC-1", "CACTCCATCTCGCTTG-1", "GCTGCGAGTCCGTCAG-1", "CTCTACGGTCCAGTAT-1", "TAGTTGGGTTCAGACT-1", "CAGTCCTAGCAATATG-1"],
                                     vec![ "CGACCTTTCCACGTGG-1", "GGAAAGCTCTCAACTT-1", "GGACATTCAGCAGTTT-1", "GACGCGTCATCTCGCT-1", "TCGGGACGTGCTCTTC-1", "GTTCATTTCGAATCCA-1", "GTGCAGCGTACACCGC-1", "CAGTCCTTCACGGTTA-1"],
                                     vec!["GAGCAGACAGACAGGT-1", "ATGTGTGCACATGTGT-1", "TCATTACTCGGAAACG-1", "TGCGTGGAGCCATCGC-1", "GTCTCGTCACCTATCC-1", "CATTATCCATTGTGCA-1", "AAGTCTGCACAGGAGT-1", "GACCAATGTCCTCTTG-1"]];
     for vec in tags.iter().enumerate(){
@@ -1,15 +1,19 @@
-extern crate bam;
 extern crate csv;
 extern crate flate2;
-extern crate itertools;
 
 // use bam::record;
-// use itertools::Itertools;
-// use differ::{Differ, Tag};
 // use bam::RecordWriter;
-use bam::record::tags::TagValue;
+use rust_htslib::bam::{self, Read, Record};
+use rust_htslib::bam::record::Aux;
+use rust_htslib::errors::Error as HtslibError;
+use flate2::read::MultiGzDecoder;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use crate::errors::{Error, Result};
 use std::str;
-use std::error::Error;
 
 // pub fn subset_bam_rust(inputbam: &str, final_tags: Vec<Vec<String>>, outputbams: Vec<String>, prefixes: Vec<String>, tag: &str) {
 //     let reader = bam::BamReader::from_path(inputbam.to_string(), 0).unwrap();
@@ -43,27 +47,27 @@ use std::error::Error;
 //     eprint!("Read {} records\n", pass_count);
 // }
 
-pub fn peekbam_rust<'a>(bam: &'a str, n: u64, field: &'a str, tag: &'a str) -> Result<Vec<String>, extendr_api::Error> {
-    let reader = bam::BamReader::from_path(bam.to_string(), 0).unwrap();
+pub fn peekbam_rust<'a>(bam: &'a str, n: u64, field: &'a str, tag: &'a str, fasta: Option<&'a str>) -> Result<Vec<String>> {
+    let mut reader = open_reader(bam, fasta).map_err(|err| Error::input(bam, err))?;
     let mut pass_count: u64 = 0;
     let mut fail_count: u64 = 0;
     let mut tags: Vec<String> = Vec::new();
     match field {
         "tag" => {
-            for record in reader {
+            for record in reader.records() {
                 match record {
                     Ok(record) => {
-                        let tag_value = get_tag(record, tag);
+                        let tag_value = get_tag_value(&record, tag.as_bytes());
                         match tag_value {
-                            Ok(tag_value) => {
+                            Some(tag_value) => {
                                 if pass_count < n {
-                                    tags.push(String::from_utf8(tag_value).unwrap());
+                                    tags.push(tag_value.to_string());
                                     pass_count+=1;
                                 } else {
                                     break;
                                 }
                             },
-                            Err(_) => fail_count+=1,
+                            None => fail_count+=1,
                         }
                     },
                     Err(_) => fail_count+=1,
@@ -71,20 +75,14 @@ pub fn peekbam_rust<'a>(bam: &'a str, n: u64, field: &'a str, tag: &'a str) -> R
             }
         },
         "name" => {
-            for record in reader {
+            for record in reader.records() {
                 match record {
                     Ok(record) => {
-                        let value: Result<Vec<u8>, extendr_api::Error> = Ok(record.name().to_vec());
-                        match value {
-                            Ok(tag_value) => {
-                                if pass_count < n {
-                                    tags.push(String::from_utf8(tag_value).unwrap());
-                                    pass_count+=1;
-                                } else {
-                                    break;
-                                }
-                            },
-                            Err(_) => fail_count+=1,
+                        if pass_count < n {
+                            tags.push(String::from_utf8_lossy(record.qname()).to_string());
+                            pass_count+=1;
+                        } else {
+                            break;
                         }
                     },
                     Err(_) => fail_count+=1,
@@ -92,29 +90,191 @@ pub fn peekbam_rust<'a>(bam: &'a str, n: u64, field: &'a str, tag: &'a str) -> R
             }
         },
         _ => {
-            eprint!("ERROR: field not recognized\n");
-            return Err(extendr_api::Error::from("field not recognized"));
+            return Err(Error::Argument(format!("field '{}' not recognized", field)));
         }
     }
     
-    eprint!("Read {} records\n", pass_count);
-    eprint!("Failed to read {} records\n", fail_count);
+    eprintln!("Read {} records", pass_count);
+    eprintln!("Failed to read {} records", fail_count);
     Ok(tags)
 }
 
-fn get_tag(record: bam::Record, tag: &str) -> Result<Vec<u8>, Box<dyn Error>> {
-    let tag_value = record.tags().get(&[tag.as_bytes()[0], tag.as_bytes()[1]]);
-    match tag_value {
-        Some(TagValue::String(array_view, _)) => {
-            let captured_tag = array_view.to_vec();
-            Ok(captured_tag)
-        },
-        Some(TagValue::Char(value)) => {
-            let captured_tag = value.to_string().as_bytes().to_vec();
-            Ok(captured_tag)
-        },        
-        _ => {
-            Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Tag not found")))
+/// An aux tag value in a form that can be hashed and compared: integers of every
+/// width as `i64`, floats by their bits, and everything else as bytes.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TagValue {
+    Int(i64),
+    /// The bits of an `f` tag.
+    Float(u32),
+    /// The bits of a `d` tag.
+    Double(u64),
+    /// A string, hex or character tag, or a read name.
+    String(Vec<u8>),
+}
+
+impl TagValue {
+    /// Every value `text` can stand for: itself as a string, and an integer or
+    /// float if it parses as one, so that `"5"` matches an integer tag of 5.
+    pub fn parse_all(text: &[u8]) -> Vec<TagValue> {
+        let mut values = vec![TagValue::String(text.to_vec())];
+        if let Ok(text) = str::from_utf8(text) {
+            let text = text.trim();
+            if let Ok(value) = text.parse::<i64>() {
+                values.push(TagValue::Int(value));
+            }
+            if let Ok(value) = text.parse::<f32>() {
+                values.push(TagValue::float(value));
+            }
+            if let Ok(value) = text.parse::<f64>() {
+                values.push(TagValue::double(value));
+            }
+        }
+        values
+    }
+
+    // adding 0.0 turns -0.0 into 0.0 so that both compare equal
+    fn float(value: f32) -> TagValue {
+        TagValue::Float((value + 0.0).to_bits())
+    }
+
+    fn double(value: f64) -> TagValue {
+        TagValue::Double((value + 0.0).to_bits())
+    }
+}
+
+impl fmt::Display for TagValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagValue::Int(value) => write!(f, "{}", value),
+            TagValue::Float(bits) => write!(f, "{}", f32::from_bits(*bits)),
+            TagValue::Double(bits) => write!(f, "{}", f64::from_bits(*bits)),
+            TagValue::String(value) => write!(f, "{}", String::from_utf8_lossy(value)),
+        }
+    }
+}
+
+/// Returns the value of aux tag `tag` of `record` in typed form. A missing tag,
+/// or an array, gives `None`.
+pub fn get_tag_value(record: &Record, tag: &[u8]) -> Option<TagValue> {
+    match record.aux(tag) {
+        Ok(Aux::Char(value)) => Some(TagValue::String(vec![value])),
+        Ok(Aux::String(value)) | Ok(Aux::HexByteArray(value)) => Some(TagValue::String(value.as_bytes().to_vec())),
+        Ok(Aux::I8(value)) => Some(TagValue::Int(value as i64)),
+        Ok(Aux::U8(value)) => Some(TagValue::Int(value as i64)),
+        Ok(Aux::I16(value)) => Some(TagValue::Int(value as i64)),
+        Ok(Aux::U16(value)) => Some(TagValue::Int(value as i64)),
+        Ok(Aux::I32(value)) => Some(TagValue::Int(value as i64)),
+        Ok(Aux::U32(value)) => Some(TagValue::Int(value as i64)),
+        Ok(Aux::Float(value)) => Some(TagValue::float(value)),
+        Ok(Aux::Double(value)) => Some(TagValue::double(value)),
+        _ => None,
+    }
+}
+
+/// True if `path` ends in `.cram`.
+pub fn is_cram<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension().is_some_and(|ext| ext == "cram")
+}
+
+/// Opens a SAM, BAM or CRAM file (htslib detects the format), decoding CRAM
+/// against `fasta` when one is given.
+pub fn open_reader<P: AsRef<Path>>(path: P, fasta: Option<&str>) -> std::result::Result<bam::Reader, HtslibError> {
+    let mut reader = bam::Reader::from_path(path)?;
+    if let Some(fasta) = fasta {
+        reader.set_reference(fasta)?;
+    }
+    Ok(reader)
+}
+
+/// Creates an output with `header`, written as CRAM against `fasta` if `path`
+/// ends in `.cram` and as BAM otherwise.
+pub fn open_writer<P: AsRef<Path>>(path: P, header: &bam::Header, fasta: Option<&str>) -> std::result::Result<bam::Writer, HtslibError> {
+    let format = if is_cram(&path) { bam::Format::Cram } else { bam::Format::Bam };
+    let mut writer = bam::Writer::from_path(path, header, format)?;
+    if let (bam::Format::Cram, Some(fasta)) = (format, fasta) {
+        writer.set_reference(fasta)?;
+    }
+    Ok(writer)
+}
+
+/// Splits a header into its text lines, without empty ones.
+pub fn header_lines(header: &bam::HeaderView) -> Vec<String> {
+    String::from_utf8_lossy(header.as_bytes())
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Parses header text lines into an htslib header.
+pub fn header_from_lines(lines: &[String]) -> bam::Header {
+    let text = lines.join("\n") + "\n";
+    bam::Header::from_template(&bam::HeaderView::from_bytes(text.as_bytes()))
+}
+
+/// Returns the value of `field` (e.g. `SN`) in a tab-separated header line.
+pub fn header_field<'b>(line: &'b str, field: &str) -> Option<&'b str> {
+    line.split('\t').skip(1).find_map(|entry| {
+        entry.strip_prefix(field).and_then(|rest| rest.strip_prefix(':'))
+    })
+}
+
+/// Returns the `SO` value of the `@HD` line, if the header has one.
+pub fn sort_order(header: &bam::HeaderView) -> Option<String> {
+    header_lines(header)
+        .iter()
+        .find(|line| line.starts_with("@HD"))
+        .and_then(|line| header_field(line, "SO"))
+        .map(String::from)
+}
+
+/// Returns the `SS` value of the `@HD` line (e.g. `queryname:lexicographical`
+/// from `samtools sort -N`), if the header has one.
+pub fn sub_sort_order(header: &bam::HeaderView) -> Option<String> {
+    header_lines(header)
+        .iter()
+        .find(|line| line.starts_with("@HD"))
+        .and_then(|line| header_field(line, "SS"))
+        .map(String::from)
+}
+
+/// Reads a barcode-to-group file, two columns of barcode and group, into the
+/// barcodes of each group in the order the groups first appear. The file is
+/// tab-separated if named `.tsv` or `.txt` and comma-separated otherwise, and
+/// gzipped if the name also ends in `.gz`.
+pub fn read_barcode_groups(path: &str, has_header: bool) -> Result<Vec<(String, Vec<Vec<u8>>)>> {
+    let file = File::open(path).map_err(|err| Error::input(path, err))?;
+    let name = path.strip_suffix(".gz").unwrap_or(path);
+    let input: Box<dyn io::Read> = if name.len() < path.len() {
+        Box::new(MultiGzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let delimiter = if name.ends_with(".tsv") || name.ends_with(".txt") { b'\t' } else { b',' };
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(has_header)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(input);
+
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let mut groups: Vec<(String, Vec<Vec<u8>>)> = Vec::new();
+    for record in reader.byte_records() {
+        let record = record.map_err(|err| Error::input(path, err))?;
+        if record.len() < 2 {
+            let line = record.position().map_or(0, |position| position.line());
+            return Err(Error::input(path, format!("line {} does not have a barcode and a group", line)));
         }
+        let group = String::from_utf8_lossy(&record[1]).to_string();
+        let pos = *index.entry(group.clone()).or_insert_with(|| {
+            groups.push((group, Vec::new()));
+            groups.len() - 1
+        });
+        groups[pos].1.push(record[0].to_vec());
+    }
+    if groups.is_empty() {
+        return Err(Error::input(path, "no barcodes found"));
     }
+    Ok(groups)
 }